@@ -0,0 +1,78 @@
+//! Booting Windows through BootNext rather then the normal boot order can
+//! change what the TPM measures. BitLocker then refuses to unlock and asks
+//! for the recovery key. We can not know if a machine is affected, so we
+//! warn once and let the user decide.
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use itertools::Itertools;
+use uuid::Uuid;
+
+use crate::disk;
+
+const BITLOCKER_OEM_ID: &[u8; 8] = b"-FVE-FS-";
+
+/// BitLocker encrypted partitions on the same disk as `esp`
+fn encrypted_partitions(esp: &Path) -> Result<Vec<PathBuf>> {
+    Ok(disk::sibling_partitions(esp)?
+        .into_iter()
+        .filter(|partition| disk::oem_id(partition).ok().as_ref() == Some(BITLOCKER_OEM_ID))
+        .collect())
+}
+
+pub struct Risk {
+    esp: PathBuf,
+    esp_guid: Uuid,
+    encrypted: Vec<PathBuf>,
+}
+
+/// None if the disk holding the ESP with partition guid `esp_guid` has no
+/// BitLocker encrypted partitions.
+pub fn risk(esp_guid: Uuid) -> Result<Option<Risk>> {
+    let Some(esp) = disk::by_partuuid(&esp_guid) else {
+        return Ok(None);
+    };
+    let encrypted = encrypted_partitions(&esp)?;
+    Ok((!encrypted.is_empty()).then_some(Risk {
+        esp,
+        esp_guid,
+        encrypted,
+    }))
+}
+
+pub enum Answer {
+    Continue,
+    ContinueDontAskAgain,
+    Abort,
+}
+
+pub fn ask_user(
+    Risk {
+        esp,
+        esp_guid,
+        encrypted,
+    }: &Risk,
+) -> Result<Answer> {
+    let list = encrypted.iter().map(|p| p.display()).join("\n  - ");
+    eprintln!(
+        "Windows boots from {} (partition guid {esp_guid}), on the same disk \
+        these partitions are encrypted with BitLocker:\n  - {list}\n\
+        Starting Windows through BootNext can make BitLocker ask for the \
+        recovery key. Make sure you have it before continuing.",
+        esp.display()
+    );
+
+    const CONTINUE: &str = "Continue";
+    const ALWAYS: &str = "Continue and do not ask again for this target";
+    const ABORT: &str = "Abort";
+    let answer = inquire::Select::new("Reboot into Windows?", vec![CONTINUE, ALWAYS, ABORT])
+        .prompt()
+        .wrap_err("Could not ask for confirmation")?;
+    Ok(match answer {
+        CONTINUE => Answer::Continue,
+        ALWAYS => Answer::ContinueDontAskAgain,
+        _ => Answer::Abort,
+    })
+}
@@ -1,16 +1,57 @@
+use std::path::{Path, PathBuf};
+
 use color_eyre::eyre::{eyre, Context, OptionExt};
 use color_eyre::{Result, Section};
 use efibootnext::Adapter;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::bootctl::{self, BootEntry};
-use crate::efi;
+use crate::bootctl::{self, BootEntry, OptionChanges};
+use crate::disk::{self, Presence};
+use crate::{bitlocker, efi, esp, kexec, logind, persistent, showln};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BootTarget {
-    Efi { pattern: String },
-    SystemdBoot { title: String, root: String },
+    Efi {
+        pattern: String,
+        /// What the entry boots, found again even if renamed or renumbered
+        #[serde(default)]
+        identity: Option<efi::Identity>,
+        /// The user knows BitLocker might ask for its recovery key
+        #[serde(default)]
+        bitlocker_acknowledged: bool,
+        /// Title of the systemd-boot entry to start when the target reboots
+        #[serde(default)]
+        return_to: Option<String>,
+    },
+    SystemdBoot {
+        title: String,
+        root: String,
+        /// Partition guid of the ESP holding the entry
+        #[serde(default)]
+        esp: Option<Uuid>,
+        /// Boot the newest entry of the distro with this sort key or machine
+        /// id, rather then the entry titled `title`
+        #[serde(default)]
+        latest: Option<String>,
+    },
+    /// A loader on an ESP without efi entry, booted through a temporary one
+    EfiLoader {
+        name: String,
+        identity: efi::Identity,
+        #[serde(default)]
+        bitlocker_acknowledged: bool,
+    },
+    /// USB sticks and such, by filesystem `UUID=...` or `LABEL=...`
+    Removable { spec: String },
+    /// Show the systemd-boot menu
+    Menu {
+        /// Seconds before the default starts, None waits for a choice
+        timeout: Option<u32>,
+        /// Show it on every boot from now on, not only the next
+        always: bool,
+    },
 }
 
 impl BootTarget {
@@ -29,54 +70,551 @@ impl BootTarget {
     }
 
     pub fn resolve(pattern: String) -> Result<Self> {
+        // Removable media is often plugged in only right before rebooting
+        if pattern.starts_with("UUID=") || pattern.starts_with("LABEL=") {
+            return Ok(BootTarget::Removable { spec: pattern });
+        }
+
         let mut adapter = efibootnext::Adapter::default();
-        if efi::boot_num(&mut adapter, &pattern)?.is_some() {
-            Ok(BootTarget::Efi { pattern })
-        } else if let Some(BootEntry { title, root, .. }) = bootctl::matching_pattern(&pattern)? {
-            Ok(BootTarget::SystemdBoot { title, root })
+        if let Some(entry) = efi::find(&mut adapter, &pattern, None)? {
+            Ok(BootTarget::Efi {
+                pattern,
+                identity: entry.identity(),
+                bitlocker_acknowledged: false,
+                return_to: None,
+            })
+        } else if let Some(BootEntry {
+            title, root, esp, ..
+        }) = bootctl::matching_pattern(&pattern, None)?
+        {
+            Ok(BootTarget::SystemdBoot {
+                title,
+                root,
+                esp,
+                latest: None,
+            })
+        } else if let Some(loader) = esp::scan(&efi::list(&mut adapter)?)?
+            .into_iter()
+            .find(|loader| loader.matches(&pattern))
+        {
+            Ok(BootTarget::EfiLoader {
+                name: loader.name.to_string(),
+                identity: loader.identity,
+                bitlocker_acknowledged: false,
+            })
         } else {
             Err(no_matching_entry_error(&mut adapter, &pattern).unwrap_err())
         }
     }
 
-    pub fn configure_next_boot(&self) -> Result<()> {
+    /// Come back to the systemd-boot entry matching `pattern` after booting
+    /// this target, rather then to the systemd-boot default
+    pub fn set_return_to(&mut self, pattern: &str) -> Result<()> {
+        let BootTarget::Efi { return_to, .. } = self else {
+            return Err(eyre!(
+                "Returning to a loader entry only works for efi targets"
+            ));
+        };
+        let entry = bootctl::matching_pattern(pattern, None)?
+            .ok_or_eyre("No systemd-boot entry to return to matches")
+            .with_note(|| format!("pattern: {pattern}"))?;
+        *return_to = Some(entry.title);
+        Ok(())
+    }
+
+    /// Follow kernel updates: boot the newest entry of the distro the
+    /// target entry belongs to
+    pub fn set_latest(&mut self) -> Result<()> {
+        let BootTarget::SystemdBoot {
+            title, esp, latest, ..
+        } = self
+        else {
+            return Err(eyre!(
+                "Only systemd-boot targets can follow the latest kernel"
+            ));
+        };
+        let entry =
+            bootctl::matching_pattern(title, *esp)?.ok_or_eyre("Could not find the boot entry")?;
+        let distro = entry
+            .distro()
+            .ok_or_eyre("The entry has no sort-key or machine-id line telling its distro")
+            .with_note(|| format!("entry: {}", entry.id()))?;
+        *latest = Some(distro.to_string());
+        Ok(())
+    }
+
+    /// Best effort, windows is only booted through EFI entries
+    pub fn is_windows(&self) -> bool {
+        match self {
+            BootTarget::Efi {
+                pattern, identity, ..
+            } => {
+                pattern.to_lowercase().contains("windows")
+                    || identity.as_ref().is_some_and(is_windows_loader)
+            }
+            BootTarget::EfiLoader { identity, .. } => is_windows_loader(identity),
+            BootTarget::SystemdBoot { .. }
+            | BootTarget::Removable { .. }
+            | BootTarget::Menu { .. } => false,
+        }
+    }
+
+    /// Windows targets on a disk with BitLocker encrypted partitions, unless
+    /// the user acknowledged that before.
+    pub fn bitlocker_risk(&self) -> Result<Option<bitlocker::Risk>> {
+        if !self.is_windows() {
+            return Ok(None);
+        }
+        let esp_guid = match self {
+            BootTarget::Efi {
+                pattern,
+                identity,
+                bitlocker_acknowledged: false,
+                ..
+            } => {
+                let mut adapter = efibootnext::Adapter::default();
+                // configure_next_boot reports a missing entry
+                let Some(entry) = efi::find(&mut adapter, pattern, identity.as_ref())? else {
+                    return Ok(None);
+                };
+                let Some(guid) = efi::partition_guid(entry.number())? else {
+                    return Ok(None);
+                };
+                guid
+            }
+            BootTarget::EfiLoader {
+                identity,
+                bitlocker_acknowledged: false,
+                ..
+            } => identity.partition,
+            _ => return Ok(None),
+        };
+        bitlocker::risk(esp_guid)
+    }
+
+    pub fn acknowledge_bitlocker(&mut self) {
+        if let BootTarget::Efi {
+            bitlocker_acknowledged,
+            ..
+        }
+        | BootTarget::EfiLoader {
+            bitlocker_acknowledged,
+            ..
+        } = self
+        {
+            *bitlocker_acknowledged = true;
+        }
+    }
+
+    /// The entry to boot if logind can configure the next boot, which needs
+    /// neither root nor setuid. None if that is not possible for this target
+    /// on this system, use `configure_next_boot` then.
+    pub fn unprivileged_entry(&self) -> Result<Option<BootEntry>> {
+        let BootTarget::SystemdBoot {
+            title,
+            root,
+            esp,
+            latest,
+        } = self
+        else {
+            return Ok(None);
+        };
+        if !logind::can_reboot_to_boot_loader_entry() {
+            return Ok(None);
+        }
+        // The entries are often only readable by root, the privileged
+        // path can then handle this target.
+        let Ok(Some(entry)) = loader_entry(title, root, latest.as_deref(), *esp) else {
+            return Ok(None);
+        };
+        // Let the privileged path report the changed root or fix the title
+        if !disk::same_root(&entry.root, root) {
+            return Ok(None);
+        }
+
+        // logind can not set BootNext, the privileged path can
+        if !matches!(systemd_boot_next(entry.esp), Ok(None)) {
+            return Ok(None);
+        }
+
+        check_root_present(&entry)?;
+        Ok(Some(entry))
+    }
+
+    /// Load the kernel of this target so it can be started with `kexec::reboot`
+    pub fn load_kexec(&self) -> Result<()> {
+        let BootTarget::SystemdBoot {
+            title,
+            root,
+            esp,
+            latest,
+        } = self
+        else {
+            return Err(eyre!("Only systemd-boot entries can be started with kexec"));
+        };
+        let entry = find_loader_entry(title, root, latest.as_deref(), *esp)?;
+        kexec::load(&entry)
+    }
+
+    /// Make this target the OS that boots by default, until `persistent::restore`
+    pub fn make_default(&self) -> Result<()> {
+        match self {
+            BootTarget::Efi {
+                pattern, identity, ..
+            } => {
+                let mut adapter = efibootnext::Adapter::default();
+                let num = match efi::find(&mut adapter, pattern, identity.as_ref())? {
+                    Some(entry) => entry.number(),
+                    None => recreate_entry(pattern, identity.as_ref())?,
+                };
+                check_efi_present(num)?;
+                persistent::backup()?;
+                efi::move_to_front(num)
+            }
+            BootTarget::SystemdBoot {
+                title,
+                root,
+                esp,
+                latest,
+            } => {
+                let entry = find_loader_entry(title, root, latest.as_deref(), *esp)?;
+                persistent::backup()?;
+                if let Some(num) = systemd_boot_next(entry.esp)? {
+                    efi::move_to_front(num)?;
+                }
+                bootctl::set_loader_entry_default(Some(entry.id()))
+                    .wrap_err("Could not configure systemd-boot default")
+            }
+            BootTarget::EfiLoader { .. } | BootTarget::Removable { .. } => Err(eyre!(
+                "Only efi and systemd-boot entries can be made the default"
+            ))
+            .suggestion("Give the loader an efi entry, for example with efibootmgr"),
+            BootTarget::Menu { .. } => Err(eyre!("The menu can not be made the default"))
+                .suggestion("Set the menu target again with: --menu-always"),
+        }
+    }
+
+    /// Arms the next boot, on systemd-boot targets with the kernel options
+    /// changed as in `options`
+    pub fn configure_next_boot(&self, options: &OptionChanges) -> Result<()> {
+        if !options.is_empty() && !matches!(self, BootTarget::SystemdBoot { .. }) {
+            return Err(eyre!(
+                "Kernel options can only be changed for systemd-boot targets"
+            ));
+        }
         match self {
-            BootTarget::Efi { pattern } => {
+            BootTarget::Efi {
+                pattern,
+                identity,
+                return_to,
+                ..
+            } => {
                 let mut adapter = efibootnext::Adapter::default();
-                let num = efi::boot_num(&mut adapter, pattern)?
-                    .ok_or_eyre("Could not find boot number")
-                    .suggestion("Try resetting boot target with: --set-target")?;
+                let num = match efi::find(&mut adapter, pattern, identity.as_ref())? {
+                    Some(entry) => entry.number(),
+                    None => recreate_entry(pattern, identity.as_ref())?,
+                };
+                check_efi_present(num)?;
                 adapter
                     .set_boot_next(num)
-                    .wrap_err("Failed to configure UEFI bootnext")
+                    .wrap_err("Failed to configure UEFI bootnext")?;
+                if let Some(title) = return_to {
+                    arm_return(title)?;
+                }
+                Ok(())
             }
-            BootTarget::SystemdBoot { title, root } => {
-                if let Some(entry) = bootctl::matching_pattern(title)? {
-                    if entry.root != *root {
-                        Err(eyre!("Root for configured OS changed"))
-                    .suggestion("If the OS was reinstalled or moved to another disk try resetting boot target with: --set-target")
-                    } else {
-                        bootctl::set_loader_entry_oneshot(entry)
-                            .wrap_err("Could not configure systemd-boot oneshot")
+            BootTarget::SystemdBoot {
+                title,
+                root,
+                esp,
+                latest,
+            } => {
+                let mut entry = if let Some(entry) =
+                    loader_entry(title, root, latest.as_deref(), *esp)?
+                {
+                    if !disk::same_root(&entry.root, root) {
+                        return Err(eyre!("Root for configured OS changed"))
+                    .suggestion("If the OS was reinstalled or moved to another disk try resetting boot target with: --set-target");
                     }
-                } else if let Some(entry) = bootctl::matching_root(root)? {
+                    entry
+                } else if let Some(entry) = bootctl::matching_root(root, *esp)? {
                     if inquire::Confirm::new(
                         "The title of the boot entry changed, \
                         do you want us to change it back?",
                     )
                     .prompt()?
                     {
-                        bootctl::rename_entry_title(&entry, title)
+                        let backup = bootctl::rename_entry_title(&entry, title)
                             .wrap_err("Failed to rename boot entry")?;
+                        println!(
+                            "Title changed back, the old entry is at {}\n\
+                            undo with: --restore-entry {}",
+                            backup.display(),
+                            entry.id()
+                        );
                     }
-
-                    bootctl::set_loader_entry_oneshot(entry)
-                        .wrap_err("Could not configure systemd-boot oneshot")
+                    entry
                 } else {
                     todo!()
+                };
+
+                check_root_present(&entry)?;
+                if !options.is_empty() {
+                    entry = bootctl::write_temporary_entry(&entry, options)
+                        .wrap_err("Could not write loader entry with changed kernel options")?;
+                }
+                ensure_systemd_boot_runs(entry.esp)?;
+                bootctl::set_loader_entry_oneshot(entry)
+                    .wrap_err("Could not configure systemd-boot oneshot")
+            }
+            BootTarget::EfiLoader { name, identity, .. } => {
+                let partition = disk::by_partuuid(&identity.partition)
+                    .ok_or_eyre("The disk with the target OS is not connected")
+                    .with_note(|| format!("looked for partition with guid: {}", identity.partition))
+                    .suggestion(
+                        "Connect the disk, or if the OS moved, reset the target with: --set-target",
+                    )?;
+                let esp = disk::mounted(&partition, "vfat")?;
+                if !esp.path().join(identity.relative_path()).is_file() {
+                    return Err(eyre!("The loader of the target OS is gone"))
+                        .with_note(|| format!("loader: {identity}"))
+                        .suggestion("Try resetting boot target with: --set-target");
+                }
+                let mut adapter = efibootnext::Adapter::default();
+                let num = efi::create_temporary_entry(
+                    &mut adapter,
+                    name,
+                    &partition,
+                    efi::Signature::Gpt(identity.partition),
+                    &identity.loader,
+                )?;
+                adapter
+                    .set_boot_next(num)
+                    .wrap_err("Failed to configure UEFI bootnext")
+            }
+            BootTarget::Removable { spec } => {
+                let device = disk::by_filesystem(spec)
+                    .ok_or_eyre("The removable media is not connected")
+                    .with_note(|| format!("looked for: {spec}"))
+                    .suggestion("Plug it in and try again")?;
+                let (partition, signature, loader) = removable_esp(&device)?;
+                let mut adapter = efibootnext::Adapter::default();
+                let num =
+                    efi::create_temporary_entry(&mut adapter, spec, &partition, signature, loader)?;
+                adapter
+                    .set_boot_next(num)
+                    .wrap_err("Failed to configure UEFI bootnext")
+            }
+            BootTarget::Menu { timeout, always } => {
+                ensure_systemd_boot_runs(None)?;
+                bootctl::set_menu_timeout_oneshot(*timeout)
+                    .wrap_err("Could not make systemd-boot show its menu")?;
+                if *always {
+                    persistent::backup()?;
+                    bootctl::set_menu_timeout(*timeout)
+                        .wrap_err("Could not make systemd-boot always show its menu")?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Undo `configure_next_boot`
+    pub fn disarm(&self) -> Result<()> {
+        match self {
+            BootTarget::Efi { return_to, .. } => {
+                efi::clear_boot_next()?;
+                if return_to.is_some() {
+                    bootctl::clear_loader_entry_oneshot()?;
                 }
+                Ok(())
+            }
+            BootTarget::EfiLoader { .. } | BootTarget::Removable { .. } => {
+                efi::clear_boot_next()?;
+                efi::remove_stale_temporary_entries(&mut efibootnext::Adapter::default())
             }
+            BootTarget::SystemdBoot { .. } => {
+                efi::clear_boot_next()?;
+                bootctl::clear_loader_entry_oneshot()?;
+                bootctl::remove_temporary_entries()
+            }
+            // a menu shown on every boot is the configuration, not an armed boot
+            BootTarget::Menu { .. } => {
+                efi::clear_boot_next()?;
+                bootctl::clear_menu_timeout_oneshot()
+            }
+        }
+    }
+}
+
+/// The newest kernel of the `latest` distro installed on `root` if set,
+/// otherwise the first entry in boot menu order with a title matching `title`
+fn loader_entry(
+    title: &str,
+    root: &str,
+    latest: Option<&str>,
+    esp: Option<Uuid>,
+) -> Result<Option<BootEntry>> {
+    match latest {
+        Some(distro) => bootctl::latest_of(distro, root, esp),
+        None => bootctl::matching_pattern(title, esp),
+    }
+}
+
+/// The entry a systemd-boot target boots, checking it still boots the same OS
+fn find_loader_entry(
+    title: &str,
+    root: &str,
+    latest: Option<&str>,
+    esp: Option<Uuid>,
+) -> Result<BootEntry> {
+    let entry = loader_entry(title, root, latest, esp)?
+        .ok_or_eyre("Could not find the boot entry")
+        .suggestion("Try resetting boot target with: --set-target")?;
+    if !disk::same_root(&entry.root, root) {
+        return Err(eyre!("Root for configured OS changed"))
+            .suggestion("If the OS was reinstalled or moved to another disk try resetting boot target with: --set-target");
+    }
+    check_root_present(&entry)?;
+    Ok(entry)
+}
+
+/// systemd-boot only reads the oneshot when it runs, that is after the
+/// target OS reboots.
+fn arm_return(title: &str) -> Result<()> {
+    let Some(entry) = bootctl::matching_pattern(title, None)? else {
+        showln!(
+            "The systemd-boot entry to return to ({title}) is gone, after the \
+            target the default OS starts. Set it again with: --return-to"
+        );
+        return Ok(());
+    };
+    bootctl::set_loader_entry_oneshot(entry)
+        .wrap_err("Could not configure systemd-boot oneshot for the return")
+}
+
+/// Firmware updates can wipe entries. If what the entry booted is still on
+/// disk offer to add the entry again.
+fn recreate_entry(pattern: &str, identity: Option<&efi::Identity>) -> Result<u16> {
+    let not_found = || {
+        Err(eyre!("Could not find boot number"))
+            .suggestion("Try resetting boot target with: --set-target")
+    };
+    let Some(identity) = identity else {
+        return not_found();
+    };
+    let Some(partition) = disk::by_partuuid(&identity.partition) else {
+        return not_found();
+    };
+    let esp = disk::mounted(&partition, "vfat")?;
+    if !esp.path().join(identity.relative_path()).is_file() {
+        return not_found();
+    }
+
+    let recreate = inquire::Confirm::new(&format!(
+        "The efi entry for {pattern} is gone but {identity} is still there, \
+        do you want us to recreate the entry?"
+    ))
+    .prompt()?;
+    if !recreate {
+        return not_found();
+    }
+    efi::create_entry(pattern, &partition, identity).wrap_err("Could not recreate efi entry")
+}
+
+/// The efi entry of the systemd-boot on the ESP with partition guid `esp`
+/// if that is not what the firmware starts by default. Windows updates like
+/// to put their boot manager first, then the oneshot would never be read.
+fn systemd_boot_next(esp: Option<Uuid>) -> Result<Option<u16>> {
+    let mut adapter = efibootnext::Adapter::default();
+    let Some(systemd_boot) = bootctl::efi_entry(&mut adapter, esp)? else {
+        showln!(
+            "Could not find the efi entry of systemd-boot, if the firmware \
+            does not start it by default the target will not boot"
+        );
+        return Ok(None);
+    };
+    let order = efi::boot_order()?;
+    Ok((order.first() != Some(&systemd_boot.number())).then_some(systemd_boot.number()))
+}
+
+/// See `systemd_boot_next`
+fn ensure_systemd_boot_runs(esp: Option<Uuid>) -> Result<()> {
+    if let Some(num) = systemd_boot_next(esp)? {
+        efibootnext::Adapter::default()
+            .set_boot_next(num)
+            .wrap_err("Failed to configure UEFI bootnext to start systemd-boot")?;
+    }
+    Ok(())
+}
+
+/// The partition on removable `device` with the fallback loader, how
+/// firmware identifies that partition and the loader. Installer images often
+/// put the filesystem we find by label on the whole disk with a small ESP
+/// next to it.
+fn removable_esp(device: &Path) -> Result<(PathBuf, efi::Signature, &'static str)> {
+    let fallback = esp::FALLBACK_LOADER
+        .ok_or_eyre("Booting removable media is not supported on this architecture")
+        .with_note(|| format!("architecture: {}", std::env::consts::ARCH))?;
+    let loader = efi::relative_path(fallback);
+    for partition in disk::partitions_on(device)? {
+        // not every partition is FAT, those do not hold a loader
+        let Ok(mounted) = disk::mounted(&partition, "vfat") else {
+            continue;
+        };
+        if !mounted.path().join(&loader).is_file() {
+            continue;
         }
+        let signature = if let Some(guid) = disk::partuuid(&partition) {
+            efi::Signature::Gpt(guid)
+        } else if let Some(signature) = disk::mbr_signature(&partition)? {
+            efi::Signature::Mbr(signature)
+        } else {
+            continue;
+        };
+        return Ok((partition, signature, fallback));
+    }
+    Err(eyre!("The removable media has no EFI loader"))
+        .with_note(|| format!("device: {}", device.display()))
+        .with_note(|| format!("looked for: {fallback}"))
+}
+
+fn is_windows_loader(identity: &efi::Identity) -> bool {
+    identity
+        .loader
+        .to_lowercase()
+        .starts_with("\\efi\\microsoft\\")
+}
+
+/// Rebooting into a target whose disk is not connected makes the firmware
+/// fall back to some other OS, better to not reboot at all.
+fn check_efi_present(num: u16) -> Result<()> {
+    // entries without a partition, such as network boot, we can not check
+    let Some(guid) = efi::partition_guid(num)? else {
+        return Ok(());
+    };
+    if disk::by_partuuid(&guid).is_none() {
+        return Err(eyre!("The disk with the target OS is not connected"))
+            .with_note(|| format!("looked for partition with guid: {guid}"))
+            .suggestion(
+                "Connect the disk, or if the OS moved, reset the target with: --set-target",
+            );
+    }
+    Ok(())
+}
+
+/// See `check_efi_present`
+fn check_root_present(entry: &BootEntry) -> Result<()> {
+    let Some(root) = entry.kernel_option("root") else {
+        return Ok(());
+    };
+    match disk::find_root(root) {
+        Presence::Present | Presence::Unknown => Ok(()),
+        Presence::Missing => Err(eyre!(
+            "The root partition of the target OS is not connected"
+        ))
+        .with_note(|| format!("root: {root}"))
+        .suggestion("Connect the disk, or if the OS moved, reset the target with: --set-target"),
     }
 }
 
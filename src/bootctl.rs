@@ -1,18 +1,26 @@
 use std::fmt::Display;
 use std::fs::{self, read_dir, read_to_string};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use color_eyre::eyre::{eyre, Context, Result};
+use color_eyre::Section;
 use efivar::efi::{Variable, VariableFlags, VariableVendor};
 use itertools::Itertools;
 use uuid::Uuid;
 
-mod efi_protection;
+mod bls;
+mod edit;
+pub(crate) mod efi_protection;
+mod uki;
 
-use crate::showln;
+use crate::disk::{self, Mounted};
+use crate::{efi, esp, showln};
 
+/// Where the ESP is mounted if we can not find them all
+const ESP: &str = "/boot/efi";
 const SYSTEMD_BOOT_UUID: &str = "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
-const SYSTEMD_BOOT: VariableVendor = const {
+pub(crate) const SYSTEMD_BOOT: VariableVendor = const {
     match Uuid::try_parse("4a67b082-0a4c-41cf-b6c7-440b29bb8c4f") {
         Err(_) => panic!("could not parse uuid"),
         Ok(uuid) => VariableVendor::Custom(uuid),
@@ -23,7 +31,24 @@ const SYSTEMD_BOOT: VariableVendor = const {
 pub struct BootEntry {
     pub title: String,
     pub root: String,
+    pub options: String,
+    /// kernel, relative to the ESP
+    pub linux: Option<String>,
+    /// initial ramdisks, relative to the ESP
+    pub initrd: Vec<String>,
+    /// Partition guid of the ESP the entry is on
+    pub esp: Option<Uuid>,
+    /// Usually the distro id from os-release, entries with the same key are
+    /// the kernels of one OS
+    sort_key: Option<String>,
+    machine_id: Option<String>,
+    version: Option<String>,
+    /// Keeps the ESP mounted while the entry is in use
+    mount: Arc<Mounted>,
+    /// As systemd-boot knows the entry: the file name without boot counter
     id: String,
+    file_name: String,
+    counter: Option<bls::Counter>,
 }
 
 impl Display for BootEntry {
@@ -33,16 +58,25 @@ impl Display for BootEntry {
 }
 
 impl BootEntry {
-    fn from_file(path: &Path) -> Option<Result<Self>> {
-        let Some(id) = path.file_name()?.to_str() else {
+    fn from_file(path: &Path, esp: &Esp) -> Option<Result<Self>> {
+        let Some(file_name) = path.file_name()?.to_str() else {
             return Some(Err(eyre!(
                 "Bootloader entry can not be represented in utf8"
             )));
         };
+        let (id, counter) = bls::split_counter(file_name);
 
         let mut title_line = None;
         let mut options_line = None;
-        let s = read_to_string(path).unwrap();
+        let mut linux_line = None;
+        let mut initrd_lines = Vec::new();
+        let mut sort_key = None;
+        let mut machine_id = None;
+        let mut version = None;
+        let s = match read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => return Some(Err(e).wrap_err("Could not read bootloader entry")),
+        };
         for line in s.lines() {
             let line = line.trim();
             match (title_line, line.strip_prefix("title ")) {
@@ -55,45 +89,261 @@ impl BootEntry {
                 (Some(_), Some(_)) => panic!("two options lines in entry"),
                 _ => (),
             }
+            if let Some(linux) = line.strip_prefix("linux ") {
+                linux_line = Some(linux.trim().to_string());
+            }
+            if let Some(initrd) = line.strip_prefix("initrd ") {
+                initrd_lines.push(initrd.trim().to_string());
+            }
+            if let Some(key) = line.strip_prefix("sort-key ") {
+                sort_key = Some(key.trim().to_string());
+            }
+            if let Some(id) = line.strip_prefix("machine-id ") {
+                machine_id = Some(id.trim().to_string());
+            }
+            if let Some(v) = line.strip_prefix("version ") {
+                version = Some(v.trim().to_string());
+            }
         }
 
         let Some((title, options)) = title_line.zip(options_line) else {
             panic!("no title and options line in boot entry with id: {id}");
         };
 
-        let Some((_, root)) = options.split(' ').find_map(|option| option.split_once('=')) else {
+        // without a root option we used to take the first option's value,
+        // keep that so targets set back then still match
+        let Some(root) = kernel_option(options, "root").or_else(|| {
+            options
+                .split(' ')
+                .find_map(|option| Some(option.split_once('=')?.1))
+        }) else {
             panic!("no title in entry with id: {id}");
         };
 
         Some(Ok(Self {
             title: title.trim().to_string(),
             root: root.to_string(),
-            id: id.to_string(),
+            options: options.trim().to_string(),
+            linux: linux_line,
+            initrd: initrd_lines,
+            esp: esp.partuuid,
+            sort_key,
+            machine_id,
+            version,
+            mount: esp.mount.clone(),
+            id,
+            file_name: file_name.to_string(),
+            counter,
         }))
     }
 
-    fn encode(&self) -> Vec<u8> {
-        self.id
-            .encode_utf16()
-            .flat_map(|char| char.to_le_bytes().into_iter())
-            .collect()
+    fn from_uki(path: &Path, esp: &Esp) -> Result<Self> {
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| eyre!("Unified kernel image name can not be represented in utf8"))?;
+        let (id, counter) = bls::split_counter(file_name);
+        let uki::Uki {
+            title,
+            options,
+            sort_key,
+            version,
+        } = uki::read(path)?;
+        let root = kernel_option(&options, "root")
+            .unwrap_or_default()
+            .to_string();
+        Ok(Self {
+            title,
+            root,
+            options,
+            linux: None,
+            initrd: Vec::new(),
+            esp: esp.partuuid,
+            sort_key,
+            machine_id: None,
+            version,
+            mount: esp.mount.clone(),
+            id,
+            file_name: file_name.to_string(),
+            counter,
+        })
+    }
+
+    /// Where a path from the entry, such as the kernel, is on this system
+    pub fn esp_path(&self, path: &str) -> PathBuf {
+        self.mount.path().join(path.trim_start_matches('/'))
+    }
+
+    /// Like `esp_path` for writing, ESPs we mounted ourselves are read only
+    /// until then
+    fn writable_esp_path(&self, path: &str) -> Result<PathBuf> {
+        self.mount.make_writable()?;
+        Ok(self.esp_path(path))
+    }
+
+    /// The value of a `key=value` kernel option
+    pub fn kernel_option(&self, key: &str) -> Option<&str> {
+        kernel_option(&self.options, key)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Boot counting ran out of tries, the entry failed to boot
+    pub fn known_bad(&self) -> bool {
+        self.counter.is_some_and(|counter| counter.left == 0)
+    }
+
+    /// What tells the kernels of one distro apart from other OS-es: the
+    /// sort key or else the machine id
+    pub fn distro(&self) -> Option<&str> {
+        self.sort_key.as_deref().or(self.machine_id.as_deref())
+    }
+}
+
+/// The value of a `key=value` option on a kernel command line. Like the
+/// kernel the last one wins.
+fn kernel_option<'a>(options: &'a str, key: &str) -> Option<&'a str> {
+    options
+        .split_whitespace()
+        .filter_map(|option| option.split_once('='))
+        .rev()
+        .find_map(|(k, value)| (k == key).then_some(value))
+}
+
+/// Kernel options to add or remove for a single boot
+#[derive(Debug, Default)]
+pub struct OptionChanges {
+    pub add: Vec<String>,
+    /// An option without `=` also removes every `key=value` with that key
+    pub remove: Vec<String>,
+}
+
+impl OptionChanges {
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+
+    fn apply(&self, options: &str) -> String {
+        let removed = |option: &str| {
+            self.remove.iter().any(|remove| {
+                option == remove
+                    || (!remove.contains('=')
+                        && option.split_once('=').is_some_and(|(key, _)| key == remove))
+            })
+        };
+        options
+            .split_whitespace()
+            .filter(|option| !removed(option))
+            .chain(self.add.iter().map(String::as_str))
+            .join(" ")
+    }
+}
+
+/// File name prefix of the entries written by [`write_temporary_entry`]
+const TEMPORARY_PREFIX: &str = "rbtw-temporary-";
+
+fn is_temporary(file_name: &str) -> bool {
+    file_name.starts_with(TEMPORARY_PREFIX)
+}
+
+/// An ESP, mounted as long as an entry from it is around
+struct Esp {
+    partuuid: Option<Uuid>,
+    mount: Arc<Mounted>,
+}
+
+/// Every ESP on every disk. Finding those needs root, without it we only
+/// see the ESP mounted at [`ESP`].
+fn esps() -> Result<Vec<Esp>> {
+    let mut esps = Vec::new();
+    for partition in esp::partitions()? {
+        // an ESP we can not mount can not have entries we could boot
+        let Ok(mount) = disk::mounted(&partition.device, "vfat") else {
+            continue;
+        };
+        esps.push(Esp {
+            partuuid: Some(partition.partuuid),
+            mount: Arc::new(mount),
+        });
+    }
+    if esps.is_empty() {
+        let device = disk::mounts()?
+            .into_iter()
+            .find(|mount| mount.mount_point == Path::new(ESP))
+            .map(|mount| mount.device);
+        esps.push(Esp {
+            partuuid: device.and_then(|device| disk::partuuid(&device)),
+            mount: Arc::new(Mounted::Already(PathBuf::from(ESP))),
+        });
+    }
+    Ok(esps)
+}
+
+fn list_esp(esp: &Esp, entries: &mut Vec<BootEntry>) -> Result<()> {
+    let dir = esp.mount.path().join("loader/entries");
+    if dir.is_dir() {
+        let found: Vec<_> = read_dir(&dir)
+            .wrap_err_with(|| format!("Could not read dir: {}", dir.display()))?
+            // like systemd-boot only .conf files, temporary entries are not
+            // an OS of their own, see: write_temporary_entry
+            .filter_ok(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                name.ends_with(".conf") && !is_temporary(&name)
+            })
+            .filter_map_ok(|e| BootEntry::from_file(&e.path(), esp))
+            .flatten()
+            .collect::<Result<_, _>>()
+            .wrap_err_with(|| format!("Could not read entry in {}", dir.display()))?;
+        entries.extend(found);
+    }
+
+    let dir = esp.mount.path().join("EFI/Linux");
+    if dir.is_dir() {
+        for file in
+            read_dir(&dir).wrap_err_with(|| format!("Could not read dir: {}", dir.display()))?
+        {
+            let path = file
+                .wrap_err("Could not list unified kernel images")?
+                .path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("efi"))
+            {
+                // like systemd-boot skip images without os-release
+                if let Ok(entry) = BootEntry::from_uki(&path, esp) {
+                    entries.push(entry);
+                }
+            }
+        }
     }
+    Ok(())
 }
 
+/// Loader entries and unified kernel images on all ESPs, in the order
+/// systemd-boot shows them
 pub fn list() -> Result<Vec<BootEntry>> {
-    read_dir("/boot/efi/loader/entries")
-        .wrap_err("Could not read dir: /boot/efi/loader/entries")?
-        .filter_map_ok(|e| BootEntry::from_file(&e.path()))
-        .flatten()
-        .collect::<Result<_, _>>()
-        .wrap_err("Could not read entry in /boot/efi/loader/entries")
+    let mut entries = Vec::new();
+    for esp in esps()? {
+        list_esp(&esp, &mut entries)?;
+    }
+    entries.sort_by(bls::compare);
+    Ok(entries)
 }
 
-pub fn matching_pattern(pattern: &str) -> Result<Option<BootEntry>> {
+/// Entries we do not know the ESP of could be on any
+fn on_esp(entry: &BootEntry, esp: Option<Uuid>) -> bool {
+    esp.is_none() || entry.esp.is_none() || entry.esp == esp
+}
+
+/// The entry with a title matching `pattern`, only looks on the ESP with
+/// partition guid `esp` if one is given.
+pub fn matching_pattern(pattern: &str, esp: Option<Uuid>) -> Result<Option<BootEntry>> {
     let entries = list()?;
     let mut matches = entries
         .iter()
-        .filter(|e| e.title.to_lowercase().contains(&pattern.to_lowercase()));
+        .filter(|e| on_esp(e, esp) && e.title.to_lowercase().contains(&pattern.to_lowercase()));
     let Some(choice) = matches.next() else {
         return Ok(None);
     };
@@ -101,12 +351,52 @@ pub fn matching_pattern(pattern: &str) -> Result<Option<BootEntry>> {
         showln!("multiple matching options");
     }
 
-    Ok(Some(choice.clone()))
+    pick(choice, &entries, |e| {
+        on_esp(e, esp) && e.title.to_lowercase().contains(&pattern.to_lowercase())
+    })
+}
+
+/// `first` unless boot counting marked it bad. Then the first good entry
+/// matching `filter`, or with the users consent `first` anyway.
+fn pick(
+    first: &BootEntry,
+    entries: &[BootEntry],
+    filter: impl Fn(&BootEntry) -> bool,
+) -> Result<Option<BootEntry>> {
+    if !first.known_bad() {
+        return Ok(Some(first.clone()));
+    }
+    // entries are sorted, bad ones last
+    if let Some(good) = entries.iter().find(|e| filter(e) && !e.known_bad()) {
+        return Ok(Some(good.clone()));
+    }
+    let boot_anyway = inquire::Confirm::new(&format!(
+        "{first} failed to boot before, it has no tries left. Boot it anyway?"
+    ))
+    .with_default(false)
+    .prompt()
+    .wrap_err("Could not ask for confirmation")?;
+    Ok(boot_anyway.then(|| first.clone()))
+}
+
+/// The newest entry of the distro with sort key or machine id `distro`. Two
+/// installs of one distro share the sort key, `root` tells them apart.
+pub fn latest_of(distro: &str, root: &str, esp: Option<Uuid>) -> Result<Option<BootEntry>> {
+    let entries = list()?;
+    let filter = |e: &BootEntry| {
+        on_esp(e, esp) && e.distro() == Some(distro) && disk::same_root(&e.root, root)
+    };
+    match entries.iter().find(|e| filter(e)) {
+        Some(first) => pick(first, &entries, filter),
+        None => Ok(None),
+    }
 }
 
-pub fn matching_root(root: &str) -> Result<Option<BootEntry>> {
+pub fn matching_root(root: &str, esp: Option<Uuid>) -> Result<Option<BootEntry>> {
     let entries = list()?;
-    let mut matches = entries.iter().filter(|e| e.root == root);
+    let mut matches = entries
+        .iter()
+        .filter(|e| on_esp(e, esp) && disk::same_root(&e.root, root));
     let Some(choice) = matches.next() else {
         return Ok(None);
     };
@@ -114,51 +404,279 @@ pub fn matching_root(root: &str) -> Result<Option<BootEntry>> {
         showln!("multiple matching options");
     }
 
-    Ok(Some(choice.clone()))
+    pick(choice, &entries, |e| {
+        on_esp(e, esp) && disk::same_root(&e.root, root)
+    })
+}
+
+/// A UTF-16 string variable systemd-boot sets for the OS it started
+fn read_loader_var(name: &str) -> Option<String> {
+    let (bytes, _) = efivar::system()
+        .read(&Variable::new_with_vendor(name, SYSTEMD_BOOT))
+        .ok()?;
+    let units: Vec<u16> = bytes
+        .as_chunks::<2>()
+        .0
+        .iter()
+        .map(|unit| u16::from_le_bytes(*unit))
+        .take_while(|unit| *unit != 0)
+        .collect();
+    Some(String::from_utf16_lossy(&units))
+}
+
+/// Where the systemd-boot that started this OS was loaded from
+fn running_systemd_boot() -> Option<efi::Identity> {
+    if !read_loader_var("LoaderInfo")?.starts_with("systemd-boot") {
+        return None;
+    }
+    Some(efi::Identity {
+        partition: Uuid::parse_str(&read_loader_var("LoaderDevicePartUUID")?).ok()?,
+        loader: read_loader_var("LoaderImageIdentifier")?,
+    })
+}
+
+/// The efi entry that starts systemd-boot from the ESP with partition guid
+/// `esp`. Prefers the entry that started the running OS, which also finds
+/// systemd-boot installed as the fallback loader.
+pub fn efi_entry(
+    adapter: &mut efibootnext::Adapter,
+    esp: Option<Uuid>,
+) -> Result<Option<efi::BootEntry>> {
+    let entries: Vec<_> = efi::list(adapter)?
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .identity()
+                .is_some_and(|identity| esp.is_none_or(|esp| esp == identity.partition))
+        })
+        .collect();
+
+    let running = running_systemd_boot().and_then(|running| {
+        entries
+            .iter()
+            .find(|entry| running.matches(&entry.device_path))
+    });
+    let by_name = || {
+        entries.iter().find(|entry| {
+            entry
+                .device_path
+                .file()
+                .and_then(|file| file.rsplit('\\').next())
+                .and_then(esp::known_loader)
+                == Some("systemd-boot")
+        })
+    };
+    Ok(running.or_else(by_name).cloned())
 }
 
 // check if this worked with:
 // sudo cat /sys/firmware/efi/efivars/LoaderEntryOneShot-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f
 pub fn set_loader_entry_oneshot(choice: BootEntry) -> Result<()> {
-    let var = Variable::new_with_vendor("LoaderEntryOneShot", SYSTEMD_BOOT);
+    write_loader_var("LoaderEntryOneShot", choice.id())
+}
+
+/// Undo `set_loader_entry_oneshot`, systemd-boot starts its default again
+pub fn clear_loader_entry_oneshot() -> Result<()> {
+    delete_loader_var("LoaderEntryOneShot")
+}
+
+fn loader_var_path(name: &str) -> PathBuf {
+    PathBuf::from(format!(
+        "/sys/firmware/efi/efivars/{name}-{SYSTEMD_BOOT_UUID}"
+    ))
+}
+
+/// Writes a UTF-16 systemd-boot variable. The kernel marks these immutable,
+/// the flag is lifted only for the write.
+fn write_loader_var(name: &str, value: &str) -> Result<()> {
+    let var = Variable::new_with_vendor(name, SYSTEMD_BOOT);
     let mut flags = VariableFlags::empty();
     flags.insert(VariableFlags::NON_VOLATILE);
     flags.insert(VariableFlags::BOOTSERVICE_ACCESS);
     flags.insert(VariableFlags::RUNTIME_ACCESS);
 
-    let path = PathBuf::from(format!(
-        "/sys/firmware/efi/efivars/LoaderEntryOneShot-{SYSTEMD_BOOT_UUID}"
-    ));
-    assert!(path.is_file());
+    let path = loader_var_path(name);
+    if path.is_file() {
+        efi_protection::remove(&path)
+            .wrap_err("Could not remove immutable flag protecting efi variable")?;
+    }
+    let value: Vec<u8> = value.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    efivar::system()
+        .write(&var, flags, &value)
+        .wrap_err("Failed to configure systemd-boot through efi variable")
+        .with_note(|| format!("variable: {name}"))?;
+    efi_protection::add(&path).wrap_err("Could not re-add immutable flag protecting efi variable")
+}
+
+fn delete_loader_var(name: &str) -> Result<()> {
+    let path = loader_var_path(name);
+    if !path.is_file() {
+        return Ok(());
+    }
     efi_protection::remove(&path)
         .wrap_err("Could not remove immutable flag protecting efi variable")?;
     efivar::system()
-        .write(&var, flags, &choice.encode())
-        .wrap_err("Failed to configure systemd-boot through efi variable")?;
-    efi_protection::add(&path)
-        .wrap_err("Could not re-add immutable flag protecting efi variable")?;
+        .delete(&Variable::new_with_vendor(name, SYSTEMD_BOOT))
+        .wrap_err("Failed to remove systemd-boot efi variable")
+        .with_note(|| format!("variable: {name}"))
+}
+
+/// The entry id systemd-boot starts by default, if set from the OS
+pub fn loader_entry_default() -> Option<String> {
+    read_loader_var("LoaderEntryDefault")
+}
+
+/// Makes systemd-boot start `id` by default, None leaves the choice to its
+/// configuration file again
+pub fn set_loader_entry_default(id: Option<&str>) -> Result<()> {
+    match id {
+        Some(id) => write_loader_var("LoaderEntryDefault", id),
+        None => delete_loader_var("LoaderEntryDefault"),
+    }
+}
+
+/// How systemd-boot reads a menu timeout, `menu-force` waits for the user
+fn menu_timeout_value(timeout: Option<u32>) -> String {
+    timeout.map_or_else(|| "menu-force".to_string(), |secs| secs.to_string())
+}
+
+/// Show the menu on the next boot only. Overrides a timeout of 0 (hidden)
+/// from loader.conf.
+pub fn set_menu_timeout_oneshot(timeout: Option<u32>) -> Result<()> {
+    write_loader_var("LoaderConfigTimeoutOneShot", &menu_timeout_value(timeout))
+}
+
+/// Undo `set_menu_timeout_oneshot`
+pub fn clear_menu_timeout_oneshot() -> Result<()> {
+    delete_loader_var("LoaderConfigTimeoutOneShot")
+}
+
+/// Show the menu on every boot, like `timeout` in loader.conf but it takes
+/// precedence over it
+pub fn set_menu_timeout(timeout: Option<u32>) -> Result<()> {
+    set_loader_config_timeout(Some(&menu_timeout_value(timeout)))
+}
+
+/// The menu timeout set from the OS, overriding loader.conf
+pub fn loader_config_timeout() -> Option<String> {
+    read_loader_var("LoaderConfigTimeout")
+}
+
+/// None leaves the timeout to loader.conf again
+pub fn set_loader_config_timeout(value: Option<&str>) -> Result<()> {
+    match value {
+        Some(value) => write_loader_var("LoaderConfigTimeout", value),
+        None => delete_loader_var("LoaderConfigTimeout"),
+    }
+}
+
+/// A copy of `entry` next to it with the kernel options changed. It is not
+/// listed as entry and is removed by [`remove_temporary_entries`] once
+/// booted.
+pub fn write_temporary_entry(entry: &BootEntry, changes: &OptionChanges) -> Result<BootEntry> {
+    if entry.file_name.ends_with(".efi") {
+        return Err(eyre!(
+            "The kernel options of a unified kernel image can not be changed"
+        ))
+        .suggestion("Its command line is signed together with the kernel");
+    }
+    let path = entry.esp_path(&format!("loader/entries/{}", entry.file_name));
+    let mut file = edit::EntryFile::read(&path)?;
+    let title = format!("{} ({})", entry.title, changes_description(changes));
+    let options = changes.apply(&entry.options);
+    file.set("title", &title);
+    file.set("options", &options);
+
+    let file_name = temporary_file_name(&entry.id);
+    file.write(&entry.writable_esp_path(&format!("loader/entries/{file_name}"))?)?;
+
+    let (id, counter) = bls::split_counter(&file_name);
+    Ok(BootEntry {
+        title,
+        root: kernel_option(&options, "root")
+            .unwrap_or(&entry.root)
+            .to_string(),
+        options,
+        id,
+        file_name,
+        counter,
+        ..entry.clone()
+    })
+}
+
+/// The copy of entry `id` gets a boot counter with no tries left. That sorts
+/// it after the original so systemd-boot never picks it as default, the
+/// oneshot names it by id which is without counter.
+fn temporary_file_name(id: &str) -> String {
+    let (stem, extension) = id.rsplit_once('.').unwrap_or((id, "conf"));
+    format!("{TEMPORARY_PREFIX}{stem}+0-0.{extension}")
+}
+
+fn changes_description(changes: &OptionChanges) -> String {
+    changes
+        .add
+        .iter()
+        .map(|option| format!("+{option}"))
+        .chain(changes.remove.iter().map(|option| format!("-{option}")))
+        .join(" ")
+}
+
+/// Removes the entries of [`write_temporary_entry`] unless the next boot
+/// still uses one
+pub fn remove_temporary_entries() -> Result<()> {
+    let next = read_loader_var("LoaderEntryOneShot");
+    for esp in esps()? {
+        let dir = esp.mount.path().join("loader/entries");
+        let Ok(files) = read_dir(&dir) else {
+            continue;
+        };
+        for file in files.flatten() {
+            let name = file.file_name().to_string_lossy().into_owned();
+            let (id, _) = bls::split_counter(&name);
+            if !is_temporary(&name) || next.as_ref() == Some(&id) {
+                continue;
+            }
+            esp.mount.make_writable()?;
+            fs::remove_file(file.path())
+                .wrap_err("Could not remove temporary loader entry")
+                .with_note(|| format!("path: {}", file.path().display()))?;
+        }
+    }
     Ok(())
 }
 
-pub(crate) fn rename_entry_title(BootEntry { id, .. }: &BootEntry, new_title: &str) -> Result<()> {
-    let entry_path = format!("/boot/efi/loader/entries/{id}");
-    let existing = fs::read_to_string(&entry_path).wrap_err("Could not read existing entry")?;
-    let renamed: String = existing
-        .lines()
-        .map(|line| {
-            if line.starts_with("title") {
-                line.chars()
-                    .skip("title".chars().count())
-                    .peekable()
-                    .peeking_take_while(|c| c.is_whitespace())
-                    .chain(new_title.chars())
-                    .collect::<String>()
-            } else {
-                line.to_string()
+/// Changes the title of a loader entry, returns where the old entry was
+/// backed up
+pub(crate) fn rename_entry_title(entry: &BootEntry, new_title: &str) -> Result<PathBuf> {
+    if entry.file_name.ends_with(".efi") {
+        return Err(eyre!(
+            "The title of a unified kernel image can not be changed"
+        ));
+    }
+    let path = entry.writable_esp_path(&format!("loader/entries/{}", entry.file_name))?;
+    edit::edit(&path, |file| file.set("title", new_title))
+}
+
+/// Puts back the newest backup of entry `entry`, made when rbtw changed
+/// it. Takes the id or file name of the entry, a boot counter is ignored.
+/// Looks on every ESP.
+pub fn restore_entry(entry: &str) -> Result<PathBuf> {
+    let (id, _) = bls::split_counter(entry);
+    let mut newest: Option<(edit::Backup, Esp)> = None;
+    for esp in esps()? {
+        if let Some(backup) = edit::backups(esp.mount.path(), &id)?.pop() {
+            if newest.as_ref().is_none_or(|(newest, _)| backup > *newest) {
+                newest = Some((backup, esp));
             }
-        })
-        .collect();
-    let tmp_entry_path = format!("/boot/efi/loader/entries/{id}_renamed");
-    fs::write(&tmp_entry_path, renamed).wrap_err("Could not write tmp entry with renamed title")?;
-    fs::rename(tmp_entry_path, entry_path).wrap_err("Could not swap existing entry with new entry")
+        }
+    }
+    let Some((edit::Backup { path: backup, .. }, esp)) = newest else {
+        return Err(eyre!("There is no backup of this loader entry"))
+            .with_note(|| format!("entry: {id}"));
+    };
+    esp.mount.make_writable()?;
+    edit::restore(esp.mount.path(), &id, &backup)
+        .wrap_err("Could not restore loader entry")
+        .with_note(|| format!("backup: {}", backup.display()))?;
+    Ok(backup)
 }
@@ -0,0 +1,296 @@
+//! The order systemd-boot shows entries in, see the "Sorting" section of the
+//! Boot Loader Specification. The first entry is what it boots by default,
+//! with multiple kernels of one distro that is the newest.
+
+use std::cmp::Ordering;
+
+use super::BootEntry;
+
+/// Boot counting: systemd-boot renames `name+LEFT-DONE.conf` on every try
+/// until the OS marks the boot good, which removes the counter.
+#[derive(Debug, Clone, Copy)]
+pub struct Counter {
+    pub left: u32,
+    pub done: u32,
+}
+
+/// Splits the counter off an entry file name: `a+2-1.conf` is entry
+/// `a.conf` with 2 tries left and 1 done.
+pub fn split_counter(file_name: &str) -> (String, Option<Counter>) {
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) => (stem, Some(extension)),
+        None => (file_name, None),
+    };
+    let parse = |n: &str| {
+        (!n.is_empty() && n.bytes().all(|c| c.is_ascii_digit()))
+            .then(|| n.parse().ok())
+            .flatten()
+    };
+    let counter = stem.rsplit_once('+').and_then(|(name, counter)| {
+        let (left, done) = counter.split_once('-').unwrap_or((counter, "0"));
+        Some((
+            name,
+            Counter {
+                left: parse(left)?,
+                done: parse(done)?,
+            },
+        ))
+    });
+    match (counter, extension) {
+        (Some((name, counter)), Some(extension)) => (format!("{name}.{extension}"), Some(counter)),
+        (Some((name, counter)), None) => (name.to_string(), Some(counter)),
+        (None, _) => (file_name.to_string(), None),
+    }
+}
+
+fn is_version_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"~-^.".contains(&c)
+}
+
+/// Compares versions like systemd's `strverscmp_improved`. Greater means
+/// newer: `6.10.1` > `6.9.12`, `1.0` > `1.0~rc1` and `1.0-2` > `1.0-1`.
+pub fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    if a.is_empty() || b.is_empty() {
+        return a.cmp(b);
+    }
+
+    let first = |s: &[u8]| s.first().copied().unwrap_or(0);
+    // the side that lacks a separator the other has is newer
+    let separator = |a: &mut &[u8], b: &mut &[u8], sep: u8| {
+        if first(a) != sep && first(b) != sep {
+            return None;
+        }
+        match (first(a) != sep).cmp(&(first(b) != sep)) {
+            Ordering::Equal => {
+                *a = &a[1..];
+                *b = &b[1..];
+                None
+            }
+            unequal => Some(unequal),
+        }
+    };
+
+    loop {
+        while !a.is_empty() && !is_version_char(a[0]) {
+            a = &a[1..];
+        }
+        while !b.is_empty() && !is_version_char(b[0]) {
+            b = &b[1..];
+        }
+
+        // pre-releases such as 123~rc1 are older
+        if let Some(order) = separator(&mut a, &mut b, b'~') {
+            return order;
+        }
+        // apart from pre-releases more segments is newer
+        if a.is_empty() || b.is_empty() {
+            return a.cmp(b);
+        }
+        // version-release, 123-9 is older then 123.1-1
+        if let Some(order) = separator(&mut a, &mut b, b'-') {
+            return order;
+        }
+        // patched releases
+        if let Some(order) = separator(&mut a, &mut b, b'^') {
+            return order;
+        }
+        // point releases
+        if let Some(order) = separator(&mut a, &mut b, b'.') {
+            return order;
+        }
+
+        let (a_len, b_len);
+        if first(a).is_ascii_digit() || first(b).is_ascii_digit() {
+            let order = first(a).is_ascii_digit().cmp(&first(b).is_ascii_digit());
+            if order.is_ne() {
+                return order;
+            }
+            while first(a) == b'0' {
+                a = &a[1..];
+            }
+            while first(b) == b'0' {
+                b = &b[1..];
+            }
+            a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+            b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+            // without leading zeros a longer number is larger
+            let order = (a_len > 0)
+                .cmp(&(b_len > 0))
+                .then(a_len.cmp(&b_len))
+                .then(a[..a_len].cmp(&b[..b_len]));
+            if order.is_ne() {
+                return order;
+            }
+        } else {
+            a_len = a.iter().take_while(|c| c.is_ascii_alphabetic()).count();
+            b_len = b.iter().take_while(|c| c.is_ascii_alphabetic()).count();
+            let shared = a_len.min(b_len);
+            let order = a[..shared].cmp(&b[..shared]).then(a_len.cmp(&b_len));
+            if order.is_ne() {
+                return order;
+            }
+        }
+        a = &a[a_len..];
+        b = &b[b_len..];
+    }
+}
+
+/// Missing values sort before present ones, like systemd's `strcmp_ptr`
+fn version_cmp_opt(a: Option<&str>, b: Option<&str>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => version_cmp(a, b),
+        (a, b) => a.cmp(&b),
+    }
+}
+
+/// Entries that failed to boot go last. Of the others those with a sort key
+/// come first, ordered by sort key, machine id and then newest version.
+/// Ties and entries without sort key go by id, newest first, then by most
+/// tries left.
+pub fn compare(a: &BootEntry, b: &BootEntry) -> Ordering {
+    let bad = a.known_bad().cmp(&b.known_bad());
+    if bad.is_ne() {
+        return bad;
+    }
+    let new_style = a.sort_key.is_none().cmp(&b.sort_key.is_none());
+    if new_style.is_ne() {
+        return new_style;
+    }
+    let by_key = if a.sort_key.is_some() {
+        a.sort_key
+            .cmp(&b.sort_key)
+            .then_with(|| a.machine_id.cmp(&b.machine_id))
+            .then_with(|| version_cmp_opt(a.version.as_deref(), b.version.as_deref()).reverse())
+    } else {
+        Ordering::Equal
+    };
+    by_key
+        .then_with(|| version_cmp(&a.id, &b.id).reverse())
+        .then_with(|| match (a.counter, b.counter) {
+            (Some(a), Some(b)) => a.left.cmp(&b.left).reverse().then(a.done.cmp(&b.done)),
+            _ => Ordering::Equal,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::disk::Mounted;
+
+    fn entry(file_name: &str) -> BootEntry {
+        let (id, counter) = split_counter(file_name);
+        BootEntry {
+            title: "Arch Linux".to_string(),
+            root: "UUID=0a3407de-014b-458b-b5c1-848e92a327a3".to_string(),
+            options: "rw".to_string(),
+            linux: Some("/vmlinuz-linux".to_string()),
+            initrd: Vec::new(),
+            esp: None,
+            sort_key: Some("arch".to_string()),
+            machine_id: Some("6a9857a393724b7a981ebb5b8495b9ea".to_string()),
+            version: Some("6.10.1-arch1".to_string()),
+            mount: Arc::new(Mounted::Already(PathBuf::from("/boot"))),
+            id,
+            file_name: file_name.to_string(),
+            counter,
+        }
+    }
+
+    #[test]
+    fn temporary_copy_sorts_after_original() {
+        let original = entry("arch.conf");
+        let copy = entry(&crate::bootctl::temporary_file_name(original.id()));
+        assert_eq!(copy.id(), "rbtw-temporary-arch.conf");
+        assert_eq!(compare(&original, &copy), Ordering::Less);
+        assert_eq!(compare(&copy, &original), Ordering::Greater);
+    }
+
+    #[test]
+    fn doc_examples() {
+        assert_eq!(version_cmp("6.10.1", "6.9.12"), Ordering::Greater);
+        assert_eq!(version_cmp("1.0", "1.0~rc1"), Ordering::Greater);
+        assert_eq!(version_cmp("1.0-2", "1.0-1"), Ordering::Greater);
+        assert_eq!(version_cmp("123-9", "123.1-1"), Ordering::Less);
+    }
+
+    #[test]
+    fn leading_zeros_are_ignored() {
+        assert_eq!(version_cmp("007", "7"), Ordering::Equal);
+        assert_eq!(version_cmp("1.010", "1.9"), Ordering::Greater);
+    }
+
+    /// The ordering systemd's own tests expect, oldest first
+    #[test]
+    fn systemd_order() {
+        let versions = [
+            "ab",
+            "abb",
+            "abc",
+            "0001",
+            "002",
+            "12",
+            "122",
+            "122.9",
+            "123~rc1",
+            "123",
+            "123-a",
+            "123-a.1",
+            "123-a1",
+            "123-a1.1",
+            "123-3",
+            "123-3.1",
+            "123^patch1",
+            "123^1",
+            "123.a-1",
+            "123.1-1",
+            "123a-1",
+            "124",
+        ];
+        for (i, a) in versions.iter().enumerate() {
+            assert_eq!(version_cmp(a, a), Ordering::Equal, "{a} = {a}");
+            for b in &versions[i + 1..] {
+                assert_eq!(version_cmp(a, b), Ordering::Less, "{a} < {b}");
+                assert_eq!(version_cmp(b, a), Ordering::Greater, "{b} > {a}");
+            }
+        }
+    }
+
+    #[test]
+    fn distro_kernels() {
+        assert_eq!(
+            version_cmp("fedora-6.5.6-300.fc39", "fedora-6.5.12-300.fc39"),
+            Ordering::Less
+        );
+    }
+
+    fn counter(file_name: &str) -> (String, Option<(u32, u32)>) {
+        let (id, counter) = split_counter(file_name);
+        (id, counter.map(|c| (c.left, c.done)))
+    }
+
+    #[test]
+    fn boot_counters() {
+        assert_eq!(counter("a+2-1.conf"), ("a.conf".into(), Some((2, 1))));
+        assert_eq!(counter("a+3.conf"), ("a.conf".into(), Some((3, 0))));
+        assert_eq!(counter("a+0-3.conf"), ("a.conf".into(), Some((0, 3))));
+        assert_eq!(counter("linux+1-2.efi"), ("linux.efi".into(), Some((1, 2))));
+        assert_eq!(counter("a+2-1"), ("a".into(), Some((2, 1))));
+    }
+
+    #[test]
+    fn not_boot_counters() {
+        for file_name in ["a.conf", "a+b.conf", "a+1-x.conf", "a+-1.conf", "a+.conf"] {
+            assert_eq!(counter(file_name), (file_name.into(), None), "{file_name}");
+        }
+    }
+
+    #[test]
+    fn missing_versions_sort_first() {
+        assert_eq!(version_cmp_opt(None, Some("1")), Ordering::Less);
+        assert_eq!(version_cmp_opt(None, None), Ordering::Equal);
+    }
+}
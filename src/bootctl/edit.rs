@@ -0,0 +1,323 @@
+//! Changes to loader entry files. The file is parsed into its lines so
+//! comments, ordering and untouched lines are written back exactly. Edits
+//! are validated, written to a temporary file, synced and then renamed over
+//! the entry so a crash or power loss never leaves a half written entry.
+//! The original is kept in [`BACKUP_DIR`] on the same ESP.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Result, Section};
+
+use super::bls;
+
+/// Relative to the root of the ESP, systemd-boot does not look here
+const BACKUP_DIR: &str = "loader/rbtw-backup";
+
+#[derive(Debug, Clone)]
+enum Line {
+    /// Comments, empty lines and anything else we keep as is
+    Other(String),
+    Field {
+        key: String,
+        value: String,
+        /// The line as read, None once changed
+        raw: Option<String>,
+    },
+}
+
+/// A loader entry as in the Boot Loader Specification: `key value` lines
+/// and `#` comments
+#[derive(Debug, Clone)]
+pub struct EntryFile {
+    lines: Vec<Line>,
+}
+
+impl EntryFile {
+    pub fn parse(text: &str) -> Self {
+        let lines = text
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    return Line::Other(line.to_string());
+                }
+                let (key, value) = trimmed
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((trimmed, ""));
+                Line::Field {
+                    key: key.to_string(),
+                    value: value.trim().to_string(),
+                    raw: Some(line.to_string()),
+                }
+            })
+            .collect();
+        Self { lines }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .wrap_err("Could not read loader entry")
+            .with_note(|| format!("path: {}", path.display()))?;
+        Ok(Self::parse(&text))
+    }
+
+    fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Field { key, value, .. } => Some((key.as_str(), value.as_str())),
+            Line::Other(_) => None,
+        })
+    }
+
+    /// Changes the first line with `key`, or adds one at the end
+    pub fn set(&mut self, key: &str, new_value: &str) {
+        let existing = self.lines.iter_mut().find_map(|line| match line {
+            Line::Field { key: k, value, raw } if k == key => Some((value, raw)),
+            _ => None,
+        });
+        match existing {
+            Some((value, raw)) => {
+                *value = new_value.to_string();
+                *raw = None;
+            }
+            None => self.lines.push(Line::Field {
+                key: key.to_string(),
+                value: new_value.to_string(),
+                raw: None,
+            }),
+        }
+    }
+
+    /// Catches edits systemd-boot, or we, could not read back
+    pub fn validate(&self) -> Result<()> {
+        let count = |key: &str| self.fields().filter(|(k, _)| *k == key).count();
+        for (key, value) in self.fields() {
+            if value.is_empty() {
+                return Err(eyre!("Loader entry line without value"))
+                    .with_note(|| format!("key: {key}"));
+            }
+            if value.contains(['\n', '\r']) {
+                return Err(eyre!("Loader entry value spans multiple lines"))
+                    .with_note(|| format!("key: {key}"));
+            }
+        }
+        // other keys, such as options and initrd, may repeat
+        if count("title") > 1 {
+            return Err(eyre!("Loader entry has more then one title line"));
+        }
+        if count("title") == 0 {
+            return Err(eyre!("Loader entry has no title"));
+        }
+        if count("linux") + count("efi") + count("uki") == 0 {
+            return Err(eyre!("Loader entry starts nothing"))
+                .with_note(|| "it needs a linux, efi or uki line");
+        }
+        Ok(())
+    }
+
+    /// Validates then atomically replaces or creates `path`
+    pub fn write(&self, path: &Path) -> Result<()> {
+        self.validate()
+            .wrap_err("Refusing to write invalid loader entry")
+            .with_note(|| format!("path: {}", path.display()))?;
+        let dir = path.parent().expect("entries are in loader/entries");
+        let file_name = path
+            .file_name()
+            .expect("entries have a file name")
+            .to_string_lossy();
+        // systemd-boot and our listing skip files not ending in .conf
+        let tmp = dir.join(format!(".{file_name}.rbtw-tmp"));
+        write_synced(&tmp, self.to_string().as_bytes())
+            .wrap_err("Could not write temporary loader entry")
+            .with_note(|| format!("path: {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .wrap_err("Could not swap existing entry with new entry")
+            .with_note(|| format!("path: {}", path.display()))?;
+        sync_dir(dir)
+    }
+}
+
+impl std::fmt::Display for EntryFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Other(line)
+                | Line::Field {
+                    raw: Some(line), ..
+                } => writeln!(f, "{line}")?,
+                Line::Field {
+                    key,
+                    value,
+                    raw: None,
+                } => writeln!(f, "{key} {value}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Makes a rename in `dir` durable
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .wrap_err("Could not sync loader entries to disk")
+        .with_note(|| format!("dir: {}", dir.display()))
+}
+
+/// `loader/entries/<entry>` -> the ESP root
+fn esp_root(entry: &Path) -> &Path {
+    entry
+        .ancestors()
+        .nth(3)
+        .expect("entries are in loader/entries on the ESP")
+}
+
+/// Backs up the entry at `path` then applies `change` to it. Returns where
+/// the backup went. Backups are named after the entry id, the file name
+/// without boot counter, as the counter changes every boot.
+pub fn edit(path: &Path, change: impl FnOnce(&mut EntryFile)) -> Result<PathBuf> {
+    let original = fs::read_to_string(path)
+        .wrap_err("Could not read loader entry")
+        .with_note(|| format!("path: {}", path.display()))?;
+    let mut entry = EntryFile::parse(&original);
+    change(&mut entry);
+    entry
+        .validate()
+        .wrap_err("Edit would break the loader entry")?;
+
+    let dir = esp_root(path).join(BACKUP_DIR);
+    fs::create_dir_all(&dir)
+        .wrap_err("Could not create backup dir")
+        .with_note(|| format!("path: {}", dir.display()))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock is after 1970")
+        .as_secs();
+    let file_name = path
+        .file_name()
+        .expect("entries have a file name")
+        .to_string_lossy();
+    let (id, _) = bls::split_counter(&file_name);
+    let backup = dir.join(format!("{id}.{now}"));
+    write_synced(&backup, original.as_bytes())
+        .wrap_err("Could not back up loader entry")
+        .with_note(|| format!("path: {}", backup.display()))?;
+    sync_dir(&dir)?;
+
+    entry.write(path)?;
+    Ok(backup)
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Backup {
+    /// Seconds since 1970 when it was made
+    pub time: u64,
+    pub path: PathBuf,
+}
+
+/// Backups of entry `id` on the ESP mounted at `esp`, oldest first
+pub fn backups(esp: &Path, id: &str) -> Result<Vec<Backup>> {
+    let dir = esp.join(BACKUP_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut found: Vec<_> = fs::read_dir(&dir)
+        .wrap_err("Could not list loader entry backups")
+        .with_note(|| format!("dir: {}", dir.display()))?
+        .flatten()
+        .filter_map(|backup| {
+            let name = backup.file_name().to_string_lossy().into_owned();
+            let time = name.strip_prefix(id)?.strip_prefix('.')?.parse().ok()?;
+            Some(Backup {
+                time,
+                path: backup.path(),
+            })
+        })
+        .collect();
+    found.sort();
+    Ok(found)
+}
+
+/// Puts `backup` back as entry `id` on the ESP mounted at `esp`. Overwrites
+/// the file that has that id now, whatever its boot counter. The backup
+/// stays, restoring it again is harmless.
+pub fn restore(esp: &Path, id: &str, backup: &Path) -> Result<()> {
+    let entry = EntryFile::read(backup)?;
+    let dir = esp.join("loader/entries");
+    let current = fs::read_dir(&dir)
+        .wrap_err("Could not list loader entries")
+        .with_note(|| format!("dir: {}", dir.display()))?
+        .flatten()
+        .map(|file| file.file_name().to_string_lossy().into_owned())
+        .find(|name| bls::split_counter(name).0 == id);
+    entry.write(&dir.join(current.as_deref().unwrap_or(id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: &str = "\
+# Boot Loader Specification type #1 entry
+title   Arch Linux
+  version 6.10.1-arch1
+
+linux   /vmlinuz-linux
+initrd  /intel-ucode.img
+initrd  /initramfs-linux.img
+# keep quiet for the gaming setup
+options root=UUID=0a3407de-014b-458b-b5c1-848e92a327a3 rw quiet
+";
+
+    #[test]
+    fn round_trips_unchanged() {
+        assert_eq!(EntryFile::parse(ENTRY).to_string(), ENTRY);
+    }
+
+    #[test]
+    fn set_only_changes_that_line() {
+        let mut entry = EntryFile::parse(ENTRY);
+        entry.set("title", "Gaming");
+        let expected = ENTRY.replace("title   Arch Linux", "title Gaming");
+        assert_eq!(entry.to_string(), expected);
+    }
+
+    #[test]
+    fn set_adds_missing_key_at_the_end() {
+        let mut entry = EntryFile::parse(ENTRY);
+        entry.set("sort-key", "arch");
+        assert_eq!(entry.to_string(), format!("{ENTRY}sort-key arch\n"));
+    }
+
+    #[test]
+    fn valid_entry() {
+        EntryFile::parse(ENTRY).validate().unwrap();
+        let two_options = format!("{ENTRY}options nvidia-drm.modeset=1\n");
+        EntryFile::parse(&two_options).validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_broken_entries() {
+        let no_title = ENTRY.replace("title   Arch Linux\n", "");
+        let two_titles = format!("{ENTRY}title Other\n");
+        let no_kernel = ENTRY.replace("linux   /vmlinuz-linux\n", "");
+        for broken in [no_title, two_titles, no_kernel] {
+            assert!(EntryFile::parse(&broken).validate().is_err(), "{broken}");
+        }
+
+        let mut entry = EntryFile::parse(ENTRY);
+        entry.set("title", "two\nlines");
+        assert!(entry.validate().is_err());
+        entry.set("title", "");
+        assert!(entry.validate().is_err());
+    }
+}
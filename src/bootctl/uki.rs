@@ -0,0 +1,123 @@
+//! Unified kernel images: a kernel, initrd and command line in one PE file
+//! in `EFI/Linux` on the ESP. systemd-boot lists them without an entry file,
+//! their title comes from the embedded os-release in the `.osrel` section.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Result, Section};
+
+pub struct Uki {
+    pub title: String,
+    /// From the `.cmdline` section, empty if there is none
+    pub options: String,
+    pub sort_key: Option<String>,
+    pub version: Option<String>,
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().expect("2 bytes"))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+/// The named sections of a PE file, only reads the headers and the
+/// requested sections so large images are cheap.
+fn read_sections(path: &Path, wanted: &[&str]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut file = File::open(path).wrap_err("Could not open unified kernel image")?;
+    let mut dos_header = [0u8; 64];
+    read_at(&mut file, 0, &mut dos_header).wrap_err("Could not read DOS header")?;
+    if &dos_header[..2] != b"MZ" {
+        return Err(eyre!("Not a PE file"));
+    }
+    let pe_offset = u64::from(u32_at(&dos_header, 0x3c));
+
+    // signature followed by the COFF file header
+    let mut coff = [0u8; 24];
+    read_at(&mut file, pe_offset, &mut coff).wrap_err("Could not read COFF header")?;
+    if &coff[..4] != b"PE\0\0" {
+        return Err(eyre!("Not a PE file"));
+    }
+    let section_count = usize::from(u16_at(&coff, 6));
+    let optional_header_size = u64::from(u16_at(&coff, 20));
+
+    let mut table = vec![0u8; section_count * 40];
+    read_at(&mut file, pe_offset + 24 + optional_header_size, &mut table)
+        .wrap_err("Could not read section table")?;
+
+    let mut sections = Vec::new();
+    for header in table.as_chunks::<40>().0 {
+        let name = String::from_utf8_lossy(&header[..8])
+            .trim_end_matches('\0')
+            .to_string();
+        if !wanted.contains(&name.as_str()) {
+            continue;
+        }
+        // the raw data is padded to the file alignment, the virtual size is not
+        let size = u32_at(header, 8).min(u32_at(header, 16));
+        let offset = u32_at(header, 20);
+        let mut data = vec![0u8; size as usize];
+        read_at(&mut file, offset.into(), &mut data)
+            .wrap_err("Could not read section")
+            .with_note(|| format!("section: {name}"))?;
+        sections.push((name, data));
+    }
+    Ok(sections)
+}
+
+fn field(os_release: &str, key: &str) -> Option<String> {
+    os_release.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.strip_prefix('=')?;
+        Some(value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Like systemd-boot: `PRETTY_NAME`, falling back to `NAME`, followed by
+/// the version if there is one
+fn title(os_release: &str) -> Option<String> {
+    let field = |key: &str| field(os_release, key);
+    let name = field("PRETTY_NAME").or_else(|| field("NAME"))?;
+    Some(
+        match field("VERSION_ID").or_else(|| field("IMAGE_VERSION")) {
+            Some(version) if !name.contains(&version) => format!("{name} ({version})"),
+            _ => name,
+        },
+    )
+}
+
+pub fn read(path: &Path) -> Result<Uki> {
+    let sections = read_sections(path, &[".osrel", ".cmdline"])
+        .with_note(|| format!("path: {}", path.display()))?;
+    let section = |name: &str| {
+        sections
+            .iter()
+            .find(|(section, _)| section == name)
+            .map(|(_, data)| {
+                String::from_utf8_lossy(data)
+                    .trim_end_matches('\0')
+                    .to_string()
+            })
+    };
+
+    let os_release = section(".osrel").unwrap_or_default();
+    let title = title(&os_release)
+        .ok_or_else(|| eyre!("Unified kernel image has no os-release with a name"))
+        .with_note(|| format!("path: {}", path.display()))?;
+    // systemd-boot sorts images by these
+    let field = |keys: &[&str]| keys.iter().find_map(|key| field(&os_release, key));
+    Ok(Uki {
+        title,
+        options: section(".cmdline").unwrap_or_default().trim().to_string(),
+        sort_key: field(&["IMAGE_ID", "ID"]),
+        version: field(&["IMAGE_VERSION", "VERSION", "VERSION_ID", "BUILD_ID"]),
+    })
+}
@@ -0,0 +1,563 @@
+//! Finding partitions and peeking at what is on them. We read sysfs and
+//! `/proc/mounts` directly so this works without udev tools or blkid.
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Context;
+use color_eyre::{Result, Section};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use uuid::Uuid;
+
+/// Temporary mounts go here, it is on a tmpfs and only writable by root
+const TMP_MOUNT_DIR: &str = "/run/rbtw";
+
+#[derive(Debug, Clone)]
+pub struct Mount {
+    pub device: PathBuf,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub read_write: bool,
+}
+
+/// `/proc/mounts` escapes spaces and such as `\040`
+fn unescape(field: &str) -> String {
+    let mut res = Vec::new();
+    let mut bytes = field.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            res.push(b);
+            continue;
+        }
+        let octal: String = bytes.by_ref().take(3).map(char::from).collect();
+        match u8::from_str_radix(&octal, 8) {
+            Ok(escaped) => res.push(escaped),
+            Err(_) => {
+                res.push(b'\\');
+                res.extend(octal.bytes());
+            }
+        }
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+pub fn mounts() -> Result<Vec<Mount>> {
+    let mounts = fs::read_to_string("/proc/mounts").wrap_err("Could not read /proc/mounts")?;
+    Ok(mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let mount_point = fields.next()?;
+            let fs_type = fields.next()?;
+            let options = fields.next()?;
+            Some(Mount {
+                device: PathBuf::from(unescape(device)),
+                mount_point: PathBuf::from(unescape(mount_point)),
+                fs_type: fs_type.to_string(),
+                read_write: options.split(',').any(|option| option == "rw"),
+            })
+        })
+        .collect())
+}
+
+/// Device nodes of all partitions the kernel knows about
+pub fn partitions() -> Result<Vec<PathBuf>> {
+    let mut partitions = Vec::new();
+    for entry in fs::read_dir("/sys/class/block").wrap_err("Could not list block devices")? {
+        let entry = entry.wrap_err("Could not list block devices")?;
+        if entry.path().join("partition").is_file() {
+            partitions.push(Path::new("/dev").join(entry.file_name()));
+        }
+    }
+    Ok(partitions)
+}
+
+/// The partition with this GPT partition guid if it is present
+pub fn by_partuuid(guid: &Uuid) -> Option<PathBuf> {
+    let link = Path::new("/dev/disk/by-partuuid").join(guid.to_string());
+    fs::canonicalize(link).ok()
+}
+
+/// How udev escapes names in /dev/disk/by-label and by-partlabel
+fn udev_escape(name: &str) -> String {
+    name.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"#+-.:=@_".contains(&b) {
+                char::from(b).to_string()
+            } else {
+                format!("\\x{b:02x}")
+            }
+        })
+        .collect()
+}
+
+fn is_block_device(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.file_type().is_block_device())
+}
+
+/// LUKS containers start with this magic, the filesystem inside (and its
+/// UUID and label) is only visible once unlocked.
+const LUKS_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+
+/// True if some partition is (or might be, when we can not read it) a LUKS
+/// container
+fn maybe_luks_present() -> bool {
+    let Ok(partitions) = partitions() else {
+        return true;
+    };
+    partitions.iter().any(|partition| {
+        let mut magic = [0u8; 6];
+        File::open(partition)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .map_or(true, |()| &magic == LUKS_MAGIC)
+    })
+}
+
+pub enum Presence {
+    Present,
+    Missing,
+    /// We could not tell, for example it is on an encrypted disk
+    Unknown,
+}
+
+/// Looks for the device in a `root=` kernel option, for example:
+/// `UUID=...`, `PARTUUID=...`, `LABEL=...` or `/dev/nvme0n1p2`
+pub fn find_root(spec: &str) -> Presence {
+    if spec.starts_with("/dev/mapper/") || spec.starts_with("/dev/dm-") {
+        // only exists after the target OS sets it up
+        return Presence::Unknown;
+    }
+    // zfs datasets, gpt-auto, nfs and such
+    let Some(link) = spec_path(spec) else {
+        return Presence::Unknown;
+    };
+    // a filesystem could be inside a LUKS volume that is not yet opened
+    let partition_level = !is_filesystem_spec(spec);
+
+    if is_block_device(&link) {
+        Presence::Present
+    } else if partition_level || !maybe_luks_present() {
+        Presence::Missing
+    } else {
+        Presence::Unknown
+    }
+}
+
+/// The device holding the filesystem with this `UUID=...` or `LABEL=...`
+pub fn by_filesystem(spec: &str) -> Option<PathBuf> {
+    if !is_filesystem_spec(spec) {
+        return None;
+    }
+    fs::canonicalize(spec_path(spec)?).ok()
+}
+
+fn is_filesystem_spec(spec: &str) -> bool {
+    spec.starts_with("UUID=") || spec.starts_with("LABEL=")
+}
+
+fn is_partition(device: &Path) -> bool {
+    let name = device.file_name().expect("device paths have a file name");
+    Path::new("/sys/class/block")
+        .join(name)
+        .join("partition")
+        .is_file()
+}
+
+/// The partitions on `device`, or `device` itself if it is a partition
+pub fn partitions_on(device: &Path) -> Result<Vec<PathBuf>> {
+    if is_partition(device) {
+        return Ok(vec![device.to_path_buf()]);
+    }
+    let name = device.file_name().expect("device paths have a file name");
+    let disk = Path::new("/sys/block").join(name);
+    let mut partitions = Vec::new();
+    for entry in fs::read_dir(&disk)
+        .wrap_err("Could not list partitions of disk")
+        .with_note(|| format!("disk: {}", device.display()))?
+    {
+        let entry = entry.wrap_err("Could not list partitions of disk")?;
+        if entry.path().join("partition").is_file() {
+            partitions.push(Path::new("/dev").join(entry.file_name()));
+        }
+    }
+    partitions.sort();
+    Ok(partitions)
+}
+
+/// The link in `dir` named `uuid`. udev keeps the case blkid reports, that
+/// is upper case for vfat and exfat, while users and tools write either.
+fn uuid_link(dir: &str, uuid: &str) -> PathBuf {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|link| link.path())
+        .find(|link| {
+            link.file_name()
+                .is_some_and(|name| name.eq_ignore_ascii_case(uuid))
+        })
+        .unwrap_or_else(|| Path::new(dir).join(uuid.to_lowercase()))
+}
+
+/// The /dev path a `root=` style spec refers to, not resolved
+fn spec_path(spec: &str) -> Option<PathBuf> {
+    Some(if let Some(uuid) = spec.strip_prefix("UUID=") {
+        uuid_link("/dev/disk/by-uuid", uuid)
+    } else if let Some(label) = spec.strip_prefix("LABEL=") {
+        Path::new("/dev/disk/by-label").join(udev_escape(label))
+    } else if let Some(uuid) = spec.strip_prefix("PARTUUID=") {
+        uuid_link("/dev/disk/by-partuuid", uuid)
+    } else if let Some(label) = spec.strip_prefix("PARTLABEL=") {
+        Path::new("/dev/disk/by-partlabel").join(udev_escape(label))
+    } else if spec.starts_with("/dev/") {
+        PathBuf::from(spec)
+    } else {
+        return None;
+    })
+}
+
+/// The filesystem uuid udev found on `device`
+fn filesystem_uuid(device: &Path) -> Option<String> {
+    fs::read_dir("/dev/disk/by-uuid")
+        .ok()?
+        .flatten()
+        .find(|link| fs::canonicalize(link.path()).is_ok_and(|target| target == device))
+        .and_then(|link| link.file_name().into_string().ok())
+}
+
+/// Device number of a block device, the same however it is named
+fn device_number(device: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(device)
+        .ok()
+        .filter(|m| m.file_type().is_block_device())
+        .map(|m| m.rdev())
+}
+
+/// True if two `root=` specs such as `UUID=...`, `PARTUUID=...`, `LABEL=...`
+/// or `/dev/nvme0n1p3` refer to the same filesystem. Specs we can not
+/// resolve, for example for a disconnected disk, are compared as text.
+pub fn same_root(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    let uuid = |spec: &str| match spec.strip_prefix("UUID=") {
+        Some(uuid) => Some(uuid.to_string()),
+        None => filesystem_uuid(&fs::canonicalize(spec_path(spec)?).ok()?),
+    };
+    if let Some((a, b)) = uuid(a).zip(uuid(b)) {
+        return a.eq_ignore_ascii_case(&b);
+    }
+    let number = |spec: &str| device_number(&spec_path(spec)?);
+    if let Some((a, b)) = number(a).zip(number(b)) {
+        return a == b;
+    }
+    false
+}
+
+/// All partitions on the same disk as `partition`, including it
+pub fn sibling_partitions(partition: &Path) -> Result<Vec<PathBuf>> {
+    let name = partition
+        .file_name()
+        .expect("device paths have a file name");
+    // sysfs places partitions in a subdirectory of their disk
+    let sys_path = fs::canonicalize(Path::new("/sys/class/block").join(name))
+        .wrap_err("Could not find partition in sysfs")
+        .with_note(|| format!("partition: {}", partition.display()))?;
+    let disk = sys_path.parent().expect("partitions are inside their disk");
+
+    let mut siblings = Vec::new();
+    for entry in fs::read_dir(disk).wrap_err("Could not list partitions of disk")? {
+        let entry = entry.wrap_err("Could not list partitions of disk")?;
+        if entry.path().join("partition").is_file() {
+            siblings.push(Path::new("/dev").join(entry.file_name()));
+        }
+    }
+    Ok(siblings)
+}
+
+/// Where a partition is on its disk, in logical blocks as UEFI counts them
+pub struct PartitionInfo {
+    pub number: u32,
+    pub start: u64,
+    pub size: u64,
+}
+
+fn read_sys_number(path: &Path) -> Result<u64> {
+    fs::read_to_string(path)
+        .wrap_err("Could not read from sysfs")
+        .with_note(|| format!("path: {}", path.display()))?
+        .trim()
+        .parse()
+        .wrap_err("Expected a number in sysfs")
+        .with_note(|| format!("path: {}", path.display()))
+}
+
+pub fn partition_info(partition: &Path) -> Result<PartitionInfo> {
+    let name = partition
+        .file_name()
+        .expect("device paths have a file name");
+    let sys_path = fs::canonicalize(Path::new("/sys/class/block").join(name))
+        .wrap_err("Could not find partition in sysfs")
+        .with_note(|| format!("partition: {}", partition.display()))?;
+    let disk = sys_path.parent().expect("partitions are inside their disk");
+
+    // sysfs always counts in 512 byte sectors, UEFI in the disks blocks
+    let block_size = read_sys_number(&disk.join("queue/logical_block_size"))?;
+    let sectors_per_block = block_size / 512;
+    Ok(PartitionInfo {
+        number: read_sys_number(&sys_path.join("partition"))?
+            .try_into()
+            .wrap_err("Partition number too large")?,
+        start: read_sys_number(&sys_path.join("start"))? / sectors_per_block,
+        size: read_sys_number(&sys_path.join("size"))? / sectors_per_block,
+    })
+}
+
+pub struct GptPartition {
+    pub device: PathBuf,
+    pub type_guid: Uuid,
+    pub partuuid: Uuid,
+}
+
+/// Block devices that are whole disks rather then partitions
+fn disks() -> Result<Vec<PathBuf>> {
+    let mut disks = Vec::new();
+    for entry in fs::read_dir("/sys/block").wrap_err("Could not list disks")? {
+        let entry = entry.wrap_err("Could not list disks")?;
+        disks.push(entry.path());
+    }
+    Ok(disks)
+}
+
+/// The partition device of partition `number` on the disk at `sys_disk`
+fn partition_device(sys_disk: &Path, number: u32) -> Result<Option<PathBuf>> {
+    for entry in fs::read_dir(sys_disk).wrap_err("Could not list partitions of disk")? {
+        let entry = entry.wrap_err("Could not list partitions of disk")?;
+        let partition = entry.path().join("partition");
+        if partition.is_file() && read_sys_number(&partition)? == u64::from(number) {
+            return Ok(Some(Path::new("/dev").join(entry.file_name())));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the partition table of a disk, see chapter 5 of the UEFI
+/// specification for the layout. Ok(None) if the disk has no GPT.
+fn read_gpt(sys_disk: &Path) -> Result<Option<Vec<GptPartition>>> {
+    let name = sys_disk.file_name().expect("sysfs paths have a file name");
+    let device = Path::new("/dev").join(name);
+    let block_size = read_sys_number(&sys_disk.join("queue/logical_block_size"))?;
+
+    let mut file = File::open(&device)
+        .wrap_err("Could not open disk")
+        .with_note(|| format!("disk: {}", device.display()))?;
+    let mut header = [0u8; 92];
+    file.seek(SeekFrom::Start(block_size))
+        .and_then(|_| file.read_exact(&mut header))
+        .wrap_err("Could not read partition table header")
+        .with_note(|| format!("disk: {}", device.display()))?;
+    if &header[..8] != b"EFI PART" {
+        return Ok(None);
+    }
+
+    let u32_at =
+        |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().expect("4 bytes"));
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().expect("8 bytes"));
+    let entry_count = u32_at(80);
+    let entry_size = u32_at(84) as usize;
+    // the header is not checksummed here, keep a corrupt one from making us
+    // allocate gigabytes. Real tables have 128 entries of 128 bytes.
+    if !(128..=4096).contains(&entry_size) || entry_count > 1024 {
+        return Ok(None);
+    }
+
+    let mut entries = vec![0u8; entry_count as usize * entry_size];
+    file.seek(SeekFrom::Start(entries_lba * block_size))
+        .and_then(|_| file.read_exact(&mut entries))
+        .wrap_err("Could not read partition table")
+        .with_note(|| format!("disk: {}", device.display()))?;
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid = Uuid::from_bytes_le(entry[..16].try_into().expect("16 bytes"));
+        if type_guid.is_nil() {
+            continue;
+        }
+        let number = i as u32 + 1;
+        let Some(device) = partition_device(sys_disk, number)? else {
+            continue;
+        };
+        partitions.push(GptPartition {
+            device,
+            type_guid,
+            partuuid: Uuid::from_bytes_le(entry[16..32].try_into().expect("16 bytes")),
+        });
+    }
+    Ok(Some(partitions))
+}
+
+/// Partitions on all disks with a GPT partition table
+pub fn gpt_partitions() -> Result<Vec<GptPartition>> {
+    let mut partitions = Vec::new();
+    for disk in disks()? {
+        // Empty card readers and such can not be read, skip them
+        if let Ok(Some(on_disk)) = read_gpt(&disk) {
+            partitions.extend(on_disk);
+        }
+    }
+    Ok(partitions)
+}
+
+/// The GPT partition guid of `partition`, None if its disk has no GPT
+pub fn partuuid(partition: &Path) -> Option<Uuid> {
+    let partition = fs::canonicalize(partition).ok()?;
+    fs::read_dir("/dev/disk/by-partuuid")
+        .ok()?
+        .flatten()
+        // MBR partitions are listed too, with an id that is not a uuid
+        .filter_map(|link| Some((Uuid::parse_str(link.file_name().to_str()?).ok()?, link)))
+        .find(|(_, link)| fs::canonicalize(link.path()).is_ok_and(|p| p == partition))
+        .map(|(guid, _)| guid)
+}
+
+/// The disk signature in the MBR of the disk holding `partition`
+pub fn mbr_signature(partition: &Path) -> Result<Option<u32>> {
+    let name = partition
+        .file_name()
+        .expect("device paths have a file name");
+    let sys_path = fs::canonicalize(Path::new("/sys/class/block").join(name))
+        .wrap_err("Could not find partition in sysfs")
+        .with_note(|| format!("partition: {}", partition.display()))?;
+    let disk = sys_path.parent().expect("partitions are inside their disk");
+    let disk = Path::new("/dev").join(disk.file_name().expect("sysfs paths have a file name"));
+
+    let mut mbr = [0u8; 512];
+    File::open(&disk)
+        .and_then(|mut file| file.read_exact(&mut mbr))
+        .wrap_err("Could not read master boot record")
+        .with_note(|| format!("disk: {}", disk.display()))?;
+    if mbr[510..] != [0x55, 0xaa] {
+        return Ok(None);
+    }
+    Ok(Some(u32::from_le_bytes(
+        mbr[440..444].try_into().expect("slice is 4 long"),
+    )))
+}
+
+/// The 8 bytes at offset 3 of the first sector. This is the OEM id of the
+/// volume boot record: `NTFS    ` for NTFS and `-FVE-FS-` for BitLocker.
+pub fn oem_id(device: &Path) -> Result<[u8; 8]> {
+    let mut file = File::open(device)
+        .wrap_err("Could not open block device")
+        .with_note(|| format!("device: {}", device.display()))?;
+    let mut oem_id = [0u8; 8];
+    file.seek(SeekFrom::Start(3))
+        .and_then(|_| file.read_exact(&mut oem_id))
+        .wrap_err("Could not read boot sector")
+        .with_note(|| format!("device: {}", device.display()))?;
+    Ok(oem_id)
+}
+
+/// A read only mount that is undone when dropped, see [`Mounted::make_writable`]
+/// for writing
+#[derive(Debug)]
+pub struct TempMount {
+    pub path: PathBuf,
+}
+
+impl TempMount {
+    const FLAGS: MsFlags = MsFlags::MS_NOSUID
+        .union(MsFlags::MS_NODEV)
+        .union(MsFlags::MS_NOEXEC);
+
+    pub fn new(device: &Path, fs_type: &str) -> Result<Self> {
+        let name = device.file_name().expect("device paths have a file name");
+        let path = Path::new(TMP_MOUNT_DIR).join(name);
+        fs::create_dir_all(&path)
+            .wrap_err("Could not create mount point")
+            .with_note(|| format!("path: {}", path.display()))?;
+        let flags = MsFlags::MS_RDONLY | TempMount::FLAGS;
+        if let Err(e) = mount(Some(device), &path, Some(fs_type), flags, None::<&str>) {
+            let _ = fs::remove_dir(&path);
+            return Err(e)
+                .wrap_err("Could not mount partition")
+                .with_note(|| format!("device: {}", device.display()))
+                .with_note(|| format!("filesystem: {fs_type}"));
+        }
+        Ok(Self { path })
+    }
+}
+
+impl Drop for TempMount {
+    fn drop(&mut self) {
+        // writers sync what they write, a lazy unmount can not lose data
+        let _ = umount2(&self.path, MntFlags::MNT_DETACH);
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+#[derive(Debug)]
+pub enum Mounted {
+    Already(PathBuf),
+    Temporary(TempMount),
+}
+
+impl Mounted {
+    pub fn path(&self) -> &Path {
+        match self {
+            Mounted::Already(path) => path,
+            Mounted::Temporary(mount) => &mount.path,
+        }
+    }
+
+    /// Remounts our own read only mount read-write. Existing mounts are left
+    /// as the system set them up.
+    pub fn make_writable(&self) -> Result<()> {
+        let Mounted::Temporary(TempMount { path }) = self else {
+            return Ok(());
+        };
+        mount(
+            None::<&str>,
+            path,
+            None::<&str>,
+            MsFlags::MS_REMOUNT | TempMount::FLAGS,
+            None::<&str>,
+        )
+        .wrap_err("Could not remount partition read-write")
+        .with_note(|| format!("mount point: {}", path.display()))
+    }
+}
+
+/// Access to the files on a partition, mounts it read only if needed
+pub fn mounted(partition: &Path, fs_type: &str) -> Result<Mounted> {
+    let existing = mounts()?
+        .into_iter()
+        .find(|m| fs::canonicalize(&m.device).is_ok_and(|device| device == partition));
+    Ok(match existing {
+        Some(mount) => Mounted::Already(mount.mount_point),
+        None => Mounted::Temporary(TempMount::new(partition, fs_type)?),
+    })
+}
+
+/// Ok(None) if the file does not exist
+pub fn read_start(path: &Path, len: usize) -> Result<Option<Vec<u8>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .wrap_err("Could not open file")
+                .with_note(|| format!("path: {}", path.display()))
+        }
+    };
+    let mut start = Vec::with_capacity(len);
+    file.take(len as u64)
+        .read_to_end(&mut start)
+        .wrap_err("Could not read file")
+        .with_note(|| format!("path: {}", path.display()))?;
+    Ok(Some(start))
+}
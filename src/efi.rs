@@ -1,56 +1,356 @@
 use std::fmt::Display;
+use std::path::Path;
 
-use color_eyre::eyre::Context;
-use color_eyre::Result;
+use color_eyre::eyre::{Context, OptionExt};
+use color_eyre::{Result, Section};
 use efibootnext::{Adapter, LoadOption};
+use efivar::efi::{Variable, VariableFlags};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::showln;
 
+mod device_path;
+
+pub use device_path::{DevicePath, Node, Signature};
+
+use crate::disk;
+
+/// The entry is shown in the boot menu and may be booted
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+/// Title prefix of entries we create to boot a loader once
+const TEMPORARY_TITLE: &str = "rbtw temporary: ";
+
 #[derive(Debug, Clone)]
 pub struct BootEntry {
     title: String,
     number: u16,
+    pub device_path: DevicePath,
+}
+
+/// What an entry boots. Unlike the title or number this does not change when
+/// the firmware renames or renumbers entries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    /// GPT partition guid of the partition with the loader
+    pub partition: Uuid,
+    /// For example `\EFI\Microsoft\Boot\bootmgfw.efi`
+    pub loader: String,
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} on partition {}", self.loader, self.partition)
+    }
+}
+
+impl Identity {
+    /// The loader relative to where its partition is mounted
+    pub fn relative_path(&self) -> String {
+        relative_path(&self.loader)
+    }
+
+    pub fn matches(&self, path: &DevicePath) -> bool {
+        // FAT is case insensitive and firmware is not consistent about case
+        path.partition_guid() == Some(self.partition)
+            && path
+                .file()
+                .is_some_and(|file| file.eq_ignore_ascii_case(&self.loader))
+    }
+}
+
+/// A loader path as firmware sees it relative to where its partition is
+/// mounted: `\EFI\BOOT\BOOTX64.EFI` becomes `EFI/BOOT/BOOTX64.EFI`
+pub fn relative_path(loader: &str) -> String {
+    loader.trim_start_matches('\\').replace('\\', "/")
+}
+
+impl BootEntry {
+    pub fn number(&self) -> u16 {
+        self.number
+    }
+
+    pub fn identity(&self) -> Option<Identity> {
+        Some(Identity {
+            partition: self.device_path.partition_guid()?,
+            loader: self.device_path.file()?.to_string(),
+        })
+    }
 }
 
 impl Display for BootEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.title)
+        write!(f, "{} (Boot{:04X}", self.title, self.number)?;
+        if !self.device_path.0.is_empty() {
+            write!(f, ", {}", self.device_path)?;
+        }
+        f.write_str(")")
     }
 }
 
-impl From<LoadOption> for BootEntry {
-    fn from(value: LoadOption) -> Self {
-        Self {
+impl TryFrom<LoadOption> for BootEntry {
+    type Error = color_eyre::Report;
+
+    fn try_from(value: LoadOption) -> Result<Self> {
+        Ok(Self {
+            device_path: read_device_path(value.number)?,
             title: value.description,
             number: value.number,
-        }
+        })
     }
 }
 
+/// Decodes the device path of the loader from a `Boot####` load option. See
+/// section 3.1.3 of the UEFI specification for the layout.
+fn read_device_path(number: u16) -> Result<DevicePath> {
+    let var = Variable::new(&format!("Boot{number:04X}"));
+    let (bytes, _) = efivar::system()
+        .read(&var)
+        .wrap_err("Could not read efi boot entry")
+        .with_note(|| format!("variable: {var}"))?;
+
+    // attributes: u32, file path list length: u16, description, file path list
+    let path_list_len = bytes.get(4..6).ok_or_eyre("Boot entry is truncated")?;
+    let path_list_len = usize::from(u16::from_le_bytes([path_list_len[0], path_list_len[1]]));
+    let (_description, rest) = device_path::read_ucs2(&bytes[6..])?;
+    let path_list = rest
+        .get(..path_list_len)
+        .ok_or_eyre("Boot entry is truncated")?;
+
+    // Firmware is creative, an odd entry should not stop us listing the rest
+    Ok(DevicePath::parse(path_list).unwrap_or_default())
+}
+
 pub fn list(adapter: &mut Adapter) -> Result<Vec<BootEntry>> {
     adapter
         .load_options()
         .wrap_err("Failed to iterate efi options")?
         .map(|res| res.wrap_err("Could not load efi entry"))
-        .map_ok(BootEntry::from)
+        .map(|res| res.and_then(BootEntry::try_from))
         .collect()
 }
 
-pub fn boot_num(adapter: &mut Adapter, boot_target: &str) -> Result<Option<u16>> {
-    let mut efi_entries = list(adapter)?
-        .into_iter()
-        .filter(|BootEntry { title, .. }| {
-            title.to_lowercase().contains(&boot_target.to_lowercase())
-        })
-        .map(|o| o.number);
+fn matching_pattern<'a>(entries: &'a [BootEntry], pattern: &str) -> Vec<&'a BootEntry> {
+    entries
+        .iter()
+        .filter(|BootEntry { title, .. }| title.to_lowercase().contains(&pattern.to_lowercase()))
+        .collect()
+}
+
+/// The entry booting `identity`, falls back to the first entry with a title
+/// matching `pattern`. Warns when the two disagree.
+pub fn find(
+    adapter: &mut Adapter,
+    pattern: &str,
+    identity: Option<&Identity>,
+) -> Result<Option<BootEntry>> {
+    let entries = list(adapter)?;
+    let by_pattern = matching_pattern(&entries, pattern);
 
-    let Some(num) = efi_entries.next() else {
+    let by_identity = identity.and_then(|identity| {
+        entries
+            .iter()
+            .find(|entry| identity.matches(&entry.device_path))
+    });
+    match (identity, by_identity) {
+        (Some(_), Some(entry)) => {
+            if !by_pattern.iter().any(|e| e.number == entry.number) {
+                showln!(
+                    "The entry we boot no longer has a title matching {pattern}, \
+                    it is now called: {entry}\n\
+                    Reset the target with --set-target if that is wrong"
+                );
+            }
+            return Ok(Some(entry.clone()));
+        }
+        (Some(identity), None) if !by_pattern.is_empty() => {
+            showln!(
+                "No efi entry boots {identity} anymore, falling back to the \
+                entry with a title matching {pattern}"
+            );
+        }
+        _ => (),
+    }
+
+    let Some(first) = by_pattern.first() else {
         return Ok(None);
     };
 
-    if efi_entries.next().is_some() {
-        showln!("multiple {boot_target} efi boot loaders, starting first, exiting");
+    if by_pattern.len() > 1 {
+        let list = by_pattern.iter().join("\n  - ");
+        showln!("multiple efi boot loaders match {pattern}, starting first of:\n  - {list}");
+    }
+    Ok(Some((*first).clone()))
+}
+
+/// Undo `Adapter::set_boot_next`, the next boot follows BootOrder again
+pub fn clear_boot_next() -> Result<()> {
+    match efivar::system().delete(&Variable::new("BootNext")) {
+        Ok(()) | Err(efivar::Error::VarNotFound { .. }) => Ok(()),
+        Err(e) => Err(e).wrap_err("Could not remove BootNext efi variable"),
+    }
+}
+
+/// The entry numbers in the order firmware tries them
+pub fn boot_order() -> Result<Vec<u16>> {
+    efivar::system()
+        .get_boot_order()
+        .wrap_err("Could not read efi boot order")
+}
+
+/// Makes `number` the first entry firmware tries, keeping the others in order
+pub fn move_to_front(number: u16) -> Result<()> {
+    let mut order = boot_order()?;
+    order.retain(|num| *num != number);
+    order.insert(0, number);
+    efivar::system()
+        .set_boot_order(order)
+        .wrap_err("Could not change efi boot order")
+}
+
+/// Restores a boot order read earlier with `boot_order`
+pub fn set_boot_order(order: Vec<u16>) -> Result<()> {
+    efivar::system()
+        .set_boot_order(order)
+        .wrap_err("Could not change efi boot order")
+}
+
+/// The GPT partition guid of the partition holding the entries loader
+pub fn partition_guid(number: u16) -> Result<Option<Uuid>> {
+    Ok(read_device_path(number)?.partition_guid())
+}
+
+/// Lowest number without a `Boot####` variable
+fn free_number() -> Result<u16> {
+    let taken: Vec<u16> = efivar::system()
+        .get_all_vars()
+        .wrap_err("Could not list efi variables")?
+        .filter(|var| var.vendor().is_efi())
+        .filter_map(|var| var.boot_var_id())
+        .collect();
+    (0..=u16::MAX)
+        .find(|num| !taken.contains(num))
+        .ok_or_eyre("All efi boot entry numbers are taken")
+}
+
+/// Writes a new `Boot####` variable starting `loader` on `partition`,
+/// returns its number
+fn write_entry(title: &str, partition: &Path, signature: Signature, loader: &str) -> Result<u16> {
+    let info = disk::partition_info(partition)?;
+    let device_path = DevicePath(vec![
+        Node::HardDrive {
+            number: info.number,
+            start: info.start,
+            size: info.size,
+            signature,
+        },
+        Node::File(loader.to_string()),
+    ]);
+    let path_list = device_path.encode()?;
+
+    // layout: see `read_device_path`
+    let mut option = Vec::new();
+    option.extend(LOAD_OPTION_ACTIVE.to_le_bytes());
+    option.extend(
+        u16::try_from(path_list.len())
+            .wrap_err("Device path too long")?
+            .to_le_bytes(),
+    );
+    option.extend(device_path::encode_ucs2(title));
+    option.extend(path_list);
+
+    let number = free_number()?;
+    let var = Variable::new(&format!("Boot{number:04X}"));
+    efivar::system()
+        .write(&var, VariableFlags::default(), &option)
+        .wrap_err("Could not write new efi boot entry")
+        .with_note(|| format!("variable: {var}"))?;
+    Ok(number)
+}
+
+/// Like `efibootmgr --create`: adds a `Boot####` entry for `identity` at
+/// the end of the boot order. Returns the new entries number.
+pub fn create_entry(title: &str, partition: &Path, identity: &Identity) -> Result<u16> {
+    let signature = Signature::Gpt(identity.partition);
+    let number = write_entry(title, partition, signature, &identity.loader)?;
+
+    // Some firmware removes entries that are not in the boot order
+    let mut vars = efivar::system();
+    let mut order = vars.get_boot_order().unwrap_or_default();
+    order.push(number);
+    vars.set_boot_order(order)
+        .wrap_err("Could not add new entry to the boot order")?;
+    Ok(number)
+}
+
+/// An entry for `loader` outside the boot order, meant to be booted once
+/// through BootNext. Removed by `remove_stale_temporary_entries` later.
+/// `signature` identifies the partition: its GPT guid or MBR disk signature.
+pub fn create_temporary_entry(
+    adapter: &mut Adapter,
+    name: &str,
+    partition: &Path,
+    signature: Signature,
+    loader: &str,
+) -> Result<u16> {
+    // left over from an earlier run that did not reboot
+    if let Some(existing) = list(adapter)?.into_iter().find(|entry| {
+        entry.title.starts_with(TEMPORARY_TITLE)
+            && entry.device_path.signature() == Some(signature)
+            && entry
+                .device_path
+                .file()
+                .is_some_and(|file| file.eq_ignore_ascii_case(loader))
+    }) {
+        return Ok(existing.number);
+    }
+    write_entry(
+        &format!("{TEMPORARY_TITLE}{name}"),
+        partition,
+        signature,
+        loader,
+    )
+    .wrap_err("Could not create temporary efi entry")
+}
+
+/// The number stored in `BootNext` or `BootCurrent` if it is set
+fn read_number_var(name: &str) -> Result<Option<u16>> {
+    match efivar::system().read(&Variable::new(name)) {
+        Ok((bytes, _)) => Ok(bytes
+            .first_chunk::<2>()
+            .map(|bytes| u16::from_le_bytes(*bytes))),
+        Err(efivar::Error::VarNotFound { .. }) => Ok(None),
+        Err(e) => Err(e)
+            .wrap_err("Could not read efi variable")
+            .with_note(|| format!("variable: {name}")),
+    }
+}
+
+/// Removes our temporary entries unless the next or current boot uses them
+pub fn remove_stale_temporary_entries(adapter: &mut Adapter) -> Result<()> {
+    let in_use = [
+        read_number_var("BootNext")?,
+        read_number_var("BootCurrent")?,
+    ];
+    let mut vars = efivar::system();
+    for entry in list(adapter)? {
+        if !entry.title.starts_with(TEMPORARY_TITLE) || in_use.contains(&Some(entry.number)) {
+            continue;
+        }
+        let var = Variable::new(&format!("Boot{:04X}", entry.number));
+        vars.delete(&var)
+            .wrap_err("Could not remove temporary efi entry")
+            .with_note(|| format!("variable: {var}"))?;
+        // Some firmware adds entries it finds to the boot order
+        if let Ok(mut order) = vars.get_boot_order() {
+            if order.contains(&entry.number) {
+                order.retain(|num| *num != entry.number);
+                vars.set_boot_order(order)
+                    .wrap_err("Could not remove temporary entry from the boot order")?;
+            }
+        }
     }
-    Ok(Some(num))
+    Ok(())
 }
@@ -0,0 +1,417 @@
+//! Decoding of the UEFI device paths stored in `Boot####` load options, see
+//! chapter 10 of the UEFI specification. We decode the nodes that help tell
+//! entries apart, others are only shown by their type.
+
+use std::fmt::Display;
+
+use color_eyre::eyre::{eyre, Context, OptionExt};
+use color_eyre::{Result, Section};
+use uuid::Uuid;
+
+mod kind {
+    pub const HARDWARE: u8 = 0x01;
+    pub const ACPI: u8 = 0x02;
+    pub const MESSAGING: u8 = 0x03;
+    pub const MEDIA: u8 = 0x04;
+    pub const END: u8 = 0x7f;
+}
+
+mod sub_kind {
+    pub const PCI: u8 = 0x01;
+    pub const ACPI: u8 = 0x01;
+    pub const USB: u8 = 0x05;
+    pub const SATA: u8 = 0x12;
+    pub const NVME: u8 = 0x17;
+    pub const HARD_DRIVE: u8 = 0x01;
+    pub const FILE_PATH: u8 = 0x04;
+    pub const END_ENTIRE: u8 = 0xff;
+}
+
+/// The ACPI hardware id of a PCI root bridge: EISA id `PNP0A03`
+const PCI_ROOT_HID: u32 = 0x0a03_41d0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signature {
+    None,
+    Mbr(u32),
+    Gpt(Uuid),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Pci {
+        device: u8,
+        function: u8,
+    },
+    Acpi {
+        hid: u32,
+        uid: u32,
+    },
+    Usb {
+        parent_port: u8,
+        interface: u8,
+    },
+    Sata {
+        hba_port: u16,
+        multiplier_port: u16,
+        lun: u16,
+    },
+    Nvme {
+        namespace: u32,
+        eui64: [u8; 8],
+    },
+    HardDrive {
+        number: u32,
+        /// in sectors
+        start: u64,
+        /// in sectors
+        size: u64,
+        signature: Signature,
+    },
+    File(String),
+    Other {
+        kind: u8,
+        sub_kind: u8,
+    },
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Node::Pci { device, function } => write!(f, "Pci({device:#x},{function:#x})"),
+            Node::Acpi { hid, uid } if *hid == PCI_ROOT_HID => write!(f, "PciRoot({uid:#x})"),
+            Node::Acpi { hid, uid } => write!(f, "Acpi({hid:#x},{uid:#x})"),
+            Node::Usb {
+                parent_port,
+                interface,
+            } => write!(f, "USB({parent_port:#x},{interface:#x})"),
+            Node::Sata {
+                hba_port,
+                multiplier_port,
+                lun,
+            } => write!(f, "Sata({hba_port:#x},{multiplier_port:#x},{lun:#x})"),
+            Node::Nvme { namespace, eui64 } => {
+                let eui64 = eui64.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>();
+                write!(f, "NVMe({namespace:#x},{})", eui64.join("-"))
+            }
+            Node::HardDrive {
+                number, signature, ..
+            } => match signature {
+                Signature::Gpt(guid) => write!(f, "HD({number},GPT,{guid})"),
+                Signature::Mbr(id) => write!(f, "HD({number},MBR,{id:#010x})"),
+                Signature::None => write!(f, "HD({number})"),
+            },
+            Node::File(path) => write!(f, "File({path})"),
+            Node::Other { kind, sub_kind } => write!(f, "Node({kind:#x},{sub_kind:#x})"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DevicePath(pub Vec<Node>);
+
+impl Display for DevicePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, node) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("/")?;
+            }
+            write!(f, "{node}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Little endian reads that fail instead of panic on truncated data
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let (head, tail) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or_eyre("Device path node is truncated")?;
+        self.0 = tail;
+        Ok(*head)
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+}
+
+/// Null terminated UCS-2, returns the string and the remaining bytes
+pub fn read_ucs2(bytes: &[u8]) -> Result<(String, &[u8])> {
+    let mut units = Vec::new();
+    let mut rest = bytes;
+    loop {
+        let (unit, tail) = rest
+            .split_first_chunk::<2>()
+            .ok_or_eyre("String is not null terminated")?;
+        rest = tail;
+        match u16::from_le_bytes(*unit) {
+            0 => break,
+            unit => units.push(unit),
+        }
+    }
+    Ok((String::from_utf16_lossy(&units), rest))
+}
+
+fn parse_node(kind: u8, sub_kind: u8, data: &[u8]) -> Result<Node> {
+    let mut r = Reader(data);
+    Ok(match (kind, sub_kind) {
+        (kind::HARDWARE, sub_kind::PCI) => Node::Pci {
+            function: r.u8()?,
+            device: r.u8()?,
+        },
+        (kind::ACPI, sub_kind::ACPI) => Node::Acpi {
+            hid: r.u32()?,
+            uid: r.u32()?,
+        },
+        (kind::MESSAGING, sub_kind::USB) => Node::Usb {
+            parent_port: r.u8()?,
+            interface: r.u8()?,
+        },
+        (kind::MESSAGING, sub_kind::SATA) => Node::Sata {
+            hba_port: r.u16()?,
+            multiplier_port: r.u16()?,
+            lun: r.u16()?,
+        },
+        (kind::MESSAGING, sub_kind::NVME) => Node::Nvme {
+            namespace: r.u32()?,
+            eui64: r.bytes()?,
+        },
+        (kind::MEDIA, sub_kind::HARD_DRIVE) => {
+            let number = r.u32()?;
+            let start = r.u64()?;
+            let size = r.u64()?;
+            let signature: [u8; 16] = r.bytes()?;
+            let _partition_format = r.u8()?;
+            let signature = match r.u8()? {
+                0x01 => Signature::Mbr(u32::from_le_bytes(
+                    signature[..4].try_into().expect("slice is 4 long"),
+                )),
+                0x02 => Signature::Gpt(Uuid::from_bytes_le(signature)),
+                _ => Signature::None,
+            };
+            Node::HardDrive {
+                number,
+                start,
+                size,
+                signature,
+            }
+        }
+        (kind::MEDIA, sub_kind::FILE_PATH) => Node::File(read_ucs2(data)?.0),
+        (kind, sub_kind) => Node::Other { kind, sub_kind },
+    })
+}
+
+impl Node {
+    fn kind(&self) -> (u8, u8) {
+        match self {
+            Node::Pci { .. } => (kind::HARDWARE, sub_kind::PCI),
+            Node::Acpi { .. } => (kind::ACPI, sub_kind::ACPI),
+            Node::Usb { .. } => (kind::MESSAGING, sub_kind::USB),
+            Node::Sata { .. } => (kind::MESSAGING, sub_kind::SATA),
+            Node::Nvme { .. } => (kind::MESSAGING, sub_kind::NVME),
+            Node::HardDrive { .. } => (kind::MEDIA, sub_kind::HARD_DRIVE),
+            Node::File(_) => (kind::MEDIA, sub_kind::FILE_PATH),
+            Node::Other { kind, sub_kind } => (*kind, *sub_kind),
+        }
+    }
+
+    fn encode_data(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match self {
+            Node::Pci { device, function } => data.extend([*function, *device]),
+            Node::Acpi { hid, uid } => {
+                data.extend(hid.to_le_bytes());
+                data.extend(uid.to_le_bytes());
+            }
+            Node::Usb {
+                parent_port,
+                interface,
+            } => data.extend([*parent_port, *interface]),
+            Node::Sata {
+                hba_port,
+                multiplier_port,
+                lun,
+            } => {
+                data.extend(hba_port.to_le_bytes());
+                data.extend(multiplier_port.to_le_bytes());
+                data.extend(lun.to_le_bytes());
+            }
+            Node::Nvme { namespace, eui64 } => {
+                data.extend(namespace.to_le_bytes());
+                data.extend(eui64);
+            }
+            Node::HardDrive {
+                number,
+                start,
+                size,
+                signature,
+            } => {
+                data.extend(number.to_le_bytes());
+                data.extend(start.to_le_bytes());
+                data.extend(size.to_le_bytes());
+                let (signature, partition_format, signature_type) = match signature {
+                    Signature::None => ([0; 16], 0x01, 0x00),
+                    Signature::Mbr(id) => {
+                        let mut bytes = [0; 16];
+                        bytes[..4].copy_from_slice(&id.to_le_bytes());
+                        (bytes, 0x01, 0x01)
+                    }
+                    Signature::Gpt(guid) => (guid.to_bytes_le(), 0x02, 0x02),
+                };
+                data.extend(signature);
+                data.extend([partition_format, signature_type]);
+            }
+            Node::File(path) => data.extend(encode_ucs2(path)),
+            Node::Other { .. } => {
+                return Err(eyre!("Can not encode device path node we did not decode"))
+                    .with_note(|| format!("node: {self}"))
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// Null terminated UCS-2
+pub fn encode_ucs2(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+impl DevicePath {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for node in &self.0 {
+            let (kind, sub_kind) = node.kind();
+            let data = node.encode_data()?;
+            let len = u16::try_from(data.len() + 4).wrap_err("Device path node too long")?;
+            bytes.extend([kind, sub_kind]);
+            bytes.extend(len.to_le_bytes());
+            bytes.extend(data);
+        }
+        bytes.extend([kind::END, sub_kind::END_ENTIRE, 4, 0]);
+        Ok(bytes)
+    }
+
+    /// Parses the first device path in a load option's file path list
+    pub fn parse(mut bytes: &[u8]) -> Result<Self> {
+        let mut nodes = Vec::new();
+        while !bytes.is_empty() {
+            let mut header = Reader(bytes);
+            let kind = header.u8()?;
+            let sub_kind = header.u8()?;
+            let len = usize::from(header.u16()?);
+            if len < 4 || len > bytes.len() {
+                return Err(eyre!("Device path node has invalid length: {len}"));
+            }
+            if kind == kind::END {
+                // An end of instance is followed by more paths to the same
+                // thing, the first is all we need.
+                break;
+            }
+            nodes.push(parse_node(kind, sub_kind, &bytes[4..len])?);
+            bytes = &bytes[len..];
+        }
+        Ok(Self(nodes))
+    }
+
+    pub fn partition_guid(&self) -> Option<Uuid> {
+        self.0.iter().find_map(|node| match node {
+            Node::HardDrive {
+                signature: Signature::Gpt(guid),
+                ..
+            } => Some(*guid),
+            _ => None,
+        })
+    }
+
+    pub fn signature(&self) -> Option<Signature> {
+        self.0.iter().find_map(|node| match node {
+            Node::HardDrive { signature, .. } => Some(*signature),
+            _ => None,
+        })
+    }
+
+    /// The loader on the partition, for example `\EFI\systemd\systemd-bootx64.efi`
+    pub fn file(&self) -> Option<&str> {
+        self.0.iter().find_map(|node| match node {
+            Node::File(path) => Some(path.as_str()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hard_drive(signature: Signature) -> Node {
+        Node::HardDrive {
+            number: 1,
+            start: 2048,
+            size: 1_048_576,
+            signature,
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let guid = Uuid::parse_str("0a3407de-014b-458b-b5c1-848e92a327a3").unwrap();
+        let file = Node::File("\\EFI\\systemd\\systemd-bootx64.efi".to_string());
+        for signature in [Signature::Gpt(guid), Signature::Mbr(0xdead_beef)] {
+            let path = DevicePath(vec![hard_drive(signature), file.clone()]);
+            let bytes = path.encode().unwrap();
+            assert_eq!(DevicePath::parse(&bytes).unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn hard_drive_layout() {
+        let bytes = DevicePath(vec![hard_drive(Signature::None)])
+            .encode()
+            .unwrap();
+        // node header, 38 bytes of data and the end of path node
+        assert_eq!(bytes[..4], [kind::MEDIA, sub_kind::HARD_DRIVE, 42, 0]);
+        assert_eq!(bytes.len(), 42 + 4);
+        assert_eq!(bytes[42..], [kind::END, sub_kind::END_ENTIRE, 4, 0]);
+    }
+
+    #[test]
+    fn stops_at_end_of_instance() {
+        let mut bytes = DevicePath(vec![Node::File("\\a.efi".to_string())])
+            .encode()
+            .unwrap();
+        let end = bytes.len() - 4;
+        // end of this instance, another path follows
+        bytes[end + 1] = 0x01;
+        bytes.extend(
+            DevicePath(vec![Node::File("\\b.efi".to_string())])
+                .encode()
+                .unwrap(),
+        );
+        let path = DevicePath::parse(&bytes).unwrap();
+        assert_eq!(path.file(), Some("\\a.efi"));
+    }
+
+    #[test]
+    fn rejects_truncated_nodes() {
+        let bytes = DevicePath(vec![hard_drive(Signature::None)])
+            .encode()
+            .unwrap();
+        assert!(DevicePath::parse(&bytes[..20]).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Loaders on the EFI system partitions. An OS is often installed on the
+//! ESP without a firmware entry, for example after a firmware update wiped
+//! the entries or when a second distro only installed its shim.
+
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use uuid::Uuid;
+
+use crate::disk;
+use crate::efi::{self, Identity};
+
+/// GPT partition type of an EFI system partition
+const ESP_TYPE: Uuid = Uuid::from_u128(0xc12a7328_f81f_11d2_ba4b_00a0c93ec93b);
+
+/// Suffix of the loaders built for the architecture we run on, None where
+/// we do not know it
+#[cfg(target_arch = "x86_64")]
+const ARCH: Option<&str> = Some("x64");
+#[cfg(target_arch = "aarch64")]
+const ARCH: Option<&str> = Some("aa64");
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ARCH: Option<&str> = None;
+
+/// What firmware boots from removable media, and from disks without entry
+#[cfg(target_arch = "x86_64")]
+pub const FALLBACK_LOADER: Option<&str> = Some("\\EFI\\BOOT\\BOOTX64.EFI");
+#[cfg(target_arch = "aarch64")]
+pub const FALLBACK_LOADER: Option<&str> = Some("\\EFI\\BOOT\\BOOTAA64.EFI");
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub const FALLBACK_LOADER: Option<&str> = None;
+
+/// Loaders worth booting by file name, with what to call them
+pub fn known_loader(file_name: &str) -> Option<&'static str> {
+    let file_name = file_name.to_lowercase();
+    let name = file_name.strip_suffix(".efi")?;
+    if name == "bootmgfw" {
+        return Some("Windows Boot Manager");
+    }
+    // the others are named after the architecture they run on
+    let arch = ARCH?;
+    if name == format!("shim{arch}") {
+        Some("shim")
+    } else if name == format!("grub{arch}") {
+        Some("GRUB")
+    } else if name == format!("systemd-boot{arch}") {
+        Some("systemd-boot")
+    } else if name == format!("boot{arch}") {
+        Some("fallback loader")
+    } else {
+        None
+    }
+}
+
+pub struct Loader {
+    pub name: &'static str,
+    pub identity: Identity,
+    /// Some `Boot####` entry starts this loader
+    pub has_entry: bool,
+}
+
+impl Display for Loader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.name, self.identity)?;
+        if !self.has_entry {
+            f.write_str(" (no efi entry)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Loader {
+    pub fn matches(&self, pattern: &str) -> bool {
+        let pattern = pattern.to_lowercase();
+        self.name.to_lowercase().contains(&pattern)
+            || self.identity.loader.to_lowercase().contains(&pattern)
+    }
+}
+
+/// EFI system partitions on all connected disks
+pub fn partitions() -> Result<Vec<disk::GptPartition>> {
+    Ok(disk::gpt_partitions()?
+        .into_iter()
+        .filter(|partition| partition.type_guid == ESP_TYPE)
+        .collect())
+}
+
+/// Known loaders in `dir` and its subdirectories, `loader` is the path of
+/// `dir` as the firmware sees it
+fn scan_dir(dir: &Path, loader: &str, depth: usize, found: &mut Vec<(&'static str, String)>) {
+    // An unreadable or odd directory should not stop the rest of the scan
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = format!("{loader}\\{name}");
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() && depth > 0 {
+            scan_dir(&entry.path(), &path, depth - 1, found);
+        } else if let Some(kind) = file_type.is_file().then(|| known_loader(&name)).flatten() {
+            found.push((kind, path));
+        }
+    }
+}
+
+/// Walks every ESP for known loaders, marking those that no `Boot####`
+/// entry starts. Needs root to mount ESPs that are not mounted.
+pub fn scan(entries: &[efi::BootEntry]) -> Result<Vec<Loader>> {
+    let mut loaders = Vec::new();
+    for partition in partitions()? {
+        // an ESP we can not mount can not have loaders we could boot
+        let Ok(mounted) = disk::mounted(&partition.device, "vfat") else {
+            continue;
+        };
+        let Some(efi_dir) = fs::read_dir(mounted.path())
+            .wrap_err("Could not list EFI system partition")?
+            .flatten()
+            .find(|entry| entry.file_name().eq_ignore_ascii_case("efi"))
+        else {
+            continue;
+        };
+
+        let mut found = Vec::new();
+        let root = format!("\\{}", efi_dir.file_name().to_string_lossy());
+        // EFI/<vendor>/ and EFI/Microsoft/Boot/
+        scan_dir(&efi_dir.path(), &root, 2, &mut found);
+        for (name, loader) in found {
+            let identity = Identity {
+                partition: partition.partuuid,
+                loader,
+            };
+            let has_entry = entries
+                .iter()
+                .any(|entry| identity.matches(&entry.device_path));
+            loaders.push(Loader {
+                name,
+                identity,
+                has_entry,
+            });
+        }
+    }
+    Ok(loaders)
+}
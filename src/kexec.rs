@@ -0,0 +1,131 @@
+//! Skip the firmware and bootloader entirely: load the kernel of a
+//! systemd-boot entry into the running kernel and let systemd shut down and
+//! jump straight into it.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::process::Command;
+
+use color_eyre::eyre::{eyre, Context, OptionExt};
+use color_eyre::{Result, Section};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::memfd::{memfd_create, MFdFlags};
+
+use crate::bootctl::BootEntry;
+
+// Not every libc target exposes this, numbers from the kernel's syscall tables:
+// arch/x86/entry/syscalls/syscall_64.tbl and include/uapi/asm-generic/unistd.h
+#[cfg(target_arch = "x86_64")]
+const SYS_KEXEC_FILE_LOAD: Option<libc::c_long> = Some(320);
+#[cfg(target_arch = "aarch64")]
+const SYS_KEXEC_FILE_LOAD: Option<libc::c_long> = Some(294);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SYS_KEXEC_FILE_LOAD: Option<libc::c_long> = None;
+
+fn kexec_file_load_nr() -> Result<libc::c_long> {
+    SYS_KEXEC_FILE_LOAD
+        .ok_or_eyre("kexec is not supported on this architecture")
+        .with_note(|| format!("architecture: {}", std::env::consts::ARCH))
+}
+
+fn kexec_file_load(
+    kernel: &File,
+    initrd: Option<&OwnedFd>,
+    cmdline: &CString,
+    flags: libc::c_int,
+) -> Result<()> {
+    let initrd = initrd.map(AsRawFd::as_raw_fd).unwrap_or(-1);
+    let cmdline = cmdline.as_bytes_with_nul();
+    let nr = kexec_file_load_nr()?;
+    let res = unsafe {
+        libc::syscall(
+            nr,
+            kernel.as_raw_fd(),
+            initrd,
+            cmdline.len(),
+            cmdline.as_ptr(),
+            flags,
+        )
+    };
+    Errno::result(res)
+        .map(|_| ())
+        .wrap_err("kexec_file_load syscall failed")
+}
+
+/// The kernel takes a single initrd. The boot loader spec allows multiple,
+/// they are cpio archives so concatenating them is what the bootloader does too.
+fn concat_initrds(entry: &BootEntry) -> Result<Option<OwnedFd>> {
+    if entry.initrd.is_empty() {
+        return Ok(None);
+    }
+
+    let memfd = memfd_create("rbtw-initrd", MFdFlags::MFD_CLOEXEC)
+        .wrap_err("Could not create in memory file for initrd")?;
+    let mut combined = File::from(memfd);
+    for initrd in &entry.initrd {
+        let path = entry.esp_path(initrd);
+        let mut part = File::open(&path)
+            .wrap_err("Could not open initrd")
+            .with_note(|| format!("path: {}", path.display()))?;
+        io::copy(&mut part, &mut combined).wrap_err("Could not copy initrd to memory")?;
+    }
+    Ok(Some(combined.into()))
+}
+
+/// Load the kernel, initrd and kernel options of the entry. It will be started
+/// by the next call to [`reboot`].
+pub fn load(entry: &BootEntry) -> Result<()> {
+    let linux = entry
+        .linux
+        .as_ref()
+        .ok_or_eyre("Boot entry has no linux kernel, only those can be started with kexec")?;
+    let path = entry.esp_path(linux);
+    let kernel = File::open(&path)
+        .wrap_err("Could not open kernel")
+        .with_note(|| format!("path: {}", path.display()))?;
+    let initrd = concat_initrds(entry)?;
+    let cmdline = CString::new(entry.options.as_str()).wrap_err("Kernel options contain a nul")?;
+
+    let flags = if initrd.is_none() {
+        libc::KEXEC_FILE_NO_INITRAMFS
+    } else {
+        0
+    };
+    kexec_file_load(&kernel, initrd.as_ref(), &cmdline, flags)
+        .with_note(|| format!("kernel: {}", path.display()))
+        .suggestion("Is the kernel signed? With secure boot on unsigned kernels are refused")
+}
+
+/// Forget a kernel loaded with [`load`] so a normal reboot does not kexec into it
+pub fn unload() -> Result<()> {
+    let nr = kexec_file_load_nr()?;
+    let res = unsafe {
+        libc::syscall(
+            nr,
+            -1,
+            -1,
+            0,
+            std::ptr::null::<libc::c_char>(),
+            libc::KEXEC_FILE_UNLOAD,
+        )
+    };
+    Errno::result(res)
+        .map(|_| ())
+        .wrap_err("Could not unload kexec kernel")
+}
+
+/// Cleanly shut down then start the kernel loaded with [`load`]
+pub fn reboot() -> Result<()> {
+    let status = Command::new("systemctl")
+        .arg("kexec")
+        .status()
+        .wrap_err("Failed to call systemctl kexec")?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(eyre!("systemctl kexec failed")).with_note(|| format!("exit status: {status}"))
+    }
+}
@@ -0,0 +1,45 @@
+//! Let systemd-logind configure the next boot. Logind writes the oneshot
+//! efi variable itself after checking with polkit that we are allowed to. We
+//! therefore need neither root nor the immutable flag handling from
+//! `bootctl::efi_protection` when going through here.
+
+use std::process::Command;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Result, Section};
+
+const DESTINATION: &str = "org.freedesktop.login1";
+const OBJECT: &str = "/org/freedesktop/login1";
+const INTERFACE: &str = "org.freedesktop.login1.Manager";
+
+fn call(method: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new("busctl")
+        .args(["call", DESTINATION, OBJECT, INTERFACE, method])
+        .args(args)
+        .output()
+        .wrap_err("Could not run busctl")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(eyre!("D-Bus call to logind failed"))
+            .with_note(|| format!("method: {method}"))
+            .with_note(|| format!("busctl said: {stderr}"));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// True if logind will set the loader entry for us without asking for a
+/// password. Any other answer (`challenge`, `no`, `na`) or logind not running
+/// at all means we need to write the efi variable ourselves.
+pub fn can_reboot_to_boot_loader_entry() -> bool {
+    call("CanRebootToBootLoaderEntry", &[]).is_ok_and(|answer| answer == r#"s "yes""#)
+}
+
+/// Same as `bootctl::set_loader_entry_oneshot` but done by logind.
+pub fn set_reboot_to_boot_loader_entry(id: &str) -> Result<()> {
+    call("SetRebootToBootLoaderEntry", &["s", id])
+        .map(|_| ())
+        .wrap_err("Could not set loader entry through logind")
+        .with_note(|| format!("entry id: {id}"))
+}
@@ -1,19 +1,33 @@
 use std::io::Write;
 use std::os::unix::fs::chown;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 use std::thread::sleep;
 use std::time::Duration;
 
 use clap::Parser;
-use color_eyre::eyre::Context;
+use color_eyre::eyre::{eyre, Context};
 use color_eyre::{Result, Section};
 
+use crate::bitlocker::Answer;
 use crate::boot_target::BootTarget;
+use crate::bootctl::OptionChanges;
+use crate::schedule::{ClockTime, When};
 
+mod bitlocker;
 mod boot_target;
 mod bootctl;
+mod disk;
 mod efi;
+mod esp;
+mod kexec;
+mod logind;
+mod ntfs;
+mod persistent;
+mod power;
+mod rtc;
+mod schedule;
 mod setuid;
+mod snapshot;
 mod store;
 
 const ROOT: u32 = 0;
@@ -28,26 +42,161 @@ struct Args {
     /// then exit without rebooting
     ///
     /// Example usage: --set-target Windows
+    ///
+    /// Removable media such as a USB stick is set by filesystem uuid or
+    /// label: --set-target LABEL=ARCH_202510
     #[arg(short, long)]
     set_target: Option<String>,
+    /// With --set-target on an efi target: after the target reboots start
+    /// the systemd-boot entry matching this instead of the default
+    ///
+    /// Example usage: --set-target Windows --return-to gaming
+    #[arg(long, requires = "set_target")]
+    return_to: Option<String>,
+    /// With --set-target on a systemd-boot entry: always boot the newest
+    /// kernel of its distro, so kernel updates do not change the target
+    #[arg(long, requires = "set_target")]
+    latest: bool,
+    /// Configure the systemd-boot menu as target: the next boot shows it
+    /// even if it is normally hidden. Then exit without rebooting.
+    #[arg(long, conflicts_with = "set_target")]
+    set_menu_target: bool,
+    /// With --set-menu-target: seconds before the menu starts the default
+    /// entry, without it the menu waits for a choice
+    #[arg(long, requires = "set_menu_target")]
+    menu_timeout: Option<u32>,
+    /// With --set-menu-target: show the menu on every boot, not only the
+    /// next one. Undo with --restore-default.
+    #[arg(long, requires = "set_menu_target")]
+    menu_always: bool,
     /// Show the target that we will boot then exit
     #[arg(short, long)]
     current_target: bool,
+    /// List the loaders on all EFI system partitions, also those without an
+    /// efi entry, then exit. Those can be targeted too, pass their name or
+    /// path to --set-target.
+    #[arg(long)]
+    scan: bool,
     /// Only configure the next reboot dont start a reboot
     #[arg(short, long)]
     no_reboot: bool,
+    /// Start the target linux directly using kexec, skipping firmware and
+    /// bootloader. Only works for systemd-boot targets. Falls back to a
+    /// normal reboot if kexec fails.
+    #[arg(short, long, conflicts_with = "no_reboot")]
+    kexec: bool,
+    /// Configure the next boot then power off instead of rebooting. The
+    /// target is booted whenever the machine is next turned on.
+    #[arg(short, long, conflicts_with_all = ["no_reboot", "kexec"])]
+    power_off: bool,
+    /// Configure the next boot then hibernate instead of rebooting. Next
+    /// time you boot this linux your session is restored.
+    #[arg(long, conflicts_with_all = ["no_reboot", "kexec", "power_off"])]
+    hibernate: bool,
+    /// Configure the next boot now but only reboot (or power off) at this
+    /// time of day. Logged in users are warned as the moment comes closer.
+    ///
+    /// Example usage: --at 08:00
+    #[arg(long, value_parser = schedule::parse_clock_time,
+        conflicts_with_all = ["no_reboot", "kexec", "hibernate"])]
+    at: Option<ClockTime>,
+    /// Configure the next boot now but only reboot (or power off) after
+    /// this delay. A number without unit is taken as minutes.
+    ///
+    /// Example usage: --in 10m
+    #[arg(long = "in", value_parser = schedule::parse_delay,
+        conflicts_with_all = ["no_reboot", "kexec", "hibernate", "at"])]
+    delay: Option<Duration>,
+    /// Configure the next boot, set the real time clock to turn the
+    /// machine on at this time of day then power off.
+    ///
+    /// Example usage: --wake-at 03:00
+    #[arg(long, value_parser = schedule::parse_clock_time,
+        conflicts_with_all = ["no_reboot", "kexec", "hibernate", "at", "delay"])]
+    wake_at: Option<ClockTime>,
+    /// The real time clock to use for --wake-at, other then the default
+    /// needs to be run with sudo
+    #[arg(long, default_value = rtc::DEFAULT_RTC)]
+    rtc: PathBuf,
+    /// Make the target the default OS for every boot rather then only the
+    /// next. Undo with --restore-default.
+    #[arg(long, conflicts_with_all = ["kexec", "cancel"])]
+    make_default: bool,
+    /// Boot a systemd-boot target once with this extra kernel option, may
+    /// be given more then once. Needs to be run with sudo.
+    ///
+    /// Example usage: --with-option nomodeset
+    #[arg(long, conflicts_with_all = ["kexec", "make_default"])]
+    with_option: Vec<String>,
+    /// Boot a systemd-boot target once without this kernel option. An
+    /// option without value such as `console` also removes `console=...`.
+    /// Needs to be run with sudo.
+    ///
+    /// Example usage: --without-option quiet
+    #[arg(long, conflicts_with_all = ["kexec", "make_default"])]
+    without_option: Vec<String>,
+    /// Put back the default OS, and whether the menu shows, as it was
+    /// before the first --make-default or --menu-always then exit
+    #[arg(long)]
+    restore_default: bool,
+    /// Put back a loader entry as it was before rbtw last changed it, then
+    /// exit. Takes the file name of the entry, any boot counter in it is
+    /// ignored.
+    ///
+    /// Example usage: --restore-entry arch.conf
+    #[arg(long)]
+    restore_entry: Option<String>,
+    /// Save the efi variables deciding what boots to this file then exit:
+    /// the boot order, every efi entry and the systemd-boot variables.
+    /// Needs to be run with sudo.
+    #[arg(long, value_name = "FILE")]
+    save_efi_snapshot: Option<PathBuf>,
+    /// Write back the efi variables saved with --save-efi-snapshot then
+    /// exit. Boot entries created since are removed. Needs to be run with
+    /// sudo.
+    #[arg(long, value_name = "FILE", conflicts_with = "save_efi_snapshot")]
+    restore_efi_snapshot: Option<PathBuf>,
+    /// Cancel a reboot scheduled with --at or --in and undo the
+    /// configured next boot
+    #[arg(long)]
+    cancel: bool,
+}
+
+impl Args {
+    fn scheduled(&self) -> Option<When> {
+        self.at.map(When::At).or(self.delay.map(When::In))
+    }
+
+    fn option_changes(&self) -> OptionChanges {
+        OptionChanges {
+            add: self.with_option.clone(),
+            remove: self.without_option.clone(),
+        }
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     color_eyre::install()?;
     let args = Args::parse();
+    // before escalating, through the setuid bit we would always be root
+    let real_root = nix::unistd::getuid().is_root();
+    // SECURITY: through the setuid bit the clock's files are written as root
+    if args.rtc != Path::new(rtc::DEFAULT_RTC) {
+        require_sudo(real_root, "Using another real time clock")?;
+    }
     let store = store::Store::open()?;
 
     if let Some(boot_target) = args.set_target {
         sudo::escalate_if_needed()
             .expect("sudo failed, you may also call rbtw with sudo in front of it");
 
-        let boot_target = BootTarget::resolve(boot_target.clone())?;
+        let mut boot_target = BootTarget::resolve(boot_target.clone())?;
+        if let Some(pattern) = &args.return_to {
+            boot_target.set_return_to(pattern)?;
+        }
+        if args.latest {
+            boot_target.set_latest()?;
+        }
         // SECURITY: do not allow booting to another OS then what was configured
         // before authenticating as super user.
         setuid::unset();
@@ -56,27 +205,146 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    if args.set_menu_target {
+        sudo::escalate_if_needed()
+            .expect("sudo failed, you may also call rbtw with sudo in front of it");
+        let boot_target = BootTarget::Menu {
+            timeout: args.menu_timeout,
+            always: args.menu_always,
+        };
+        // SECURITY: see --set-target
+        setuid::unset();
+        store.set_data(&boot_target.to_bytes())?;
+        println!("Boot target configured! Run again to reboot to it");
+        return Ok(());
+    }
+
+    if args.scan {
+        sudo::escalate_if_needed()
+            .expect("sudo failed, you may also call rbtw with sudo in front of it");
+        let entries = efi::list(&mut efibootnext::Adapter::default())?;
+        let loaders = esp::scan(&entries).wrap_err("Could not scan EFI system partitions")?;
+        if loaders.is_empty() {
+            println!("Found no known loaders on the EFI system partitions");
+        }
+        for loader in loaders {
+            println!("- {loader}");
+        }
+        return Ok(());
+    }
+
+    if args.restore_default {
+        sudo::escalate_if_needed()
+            .expect("sudo failed, you may also call rbtw with sudo in front of it");
+        persistent::restore().wrap_err("Could not restore the default OS")?;
+        println!("Default OS restored");
+        return Ok(());
+    }
+
+    if let Some(path) = &args.save_efi_snapshot {
+        // SECURITY: writes to any path, through the setuid bit as root
+        require_sudo(real_root, "Saving efi variables")?;
+        let count = snapshot::save(path).wrap_err("Could not save efi variables")?;
+        println!(
+            "Saved {count} efi variables, restore with: --restore-efi-snapshot {}",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    if let Some(path) = &args.restore_efi_snapshot {
+        // SECURITY: the snapshot can boot anything, not only the target
+        // configured when authenticating as super user
+        require_sudo(real_root, "Restoring efi variables")?;
+        snapshot::restore(path).wrap_err("Could not restore efi variables")?;
+        println!("Efi variables restored");
+        return Ok(());
+    }
+
+    if let Some(entry) = &args.restore_entry {
+        sudo::escalate_if_needed()
+            .expect("sudo failed, you may also call rbtw with sudo in front of it");
+        let backup = bootctl::restore_entry(entry)?;
+        println!("Loader entry restored from {}", backup.display());
+        return Ok(());
+    }
+
     if store.data_bytes.is_empty() {
         println!("No boot target configured, please set one with: --set-target");
         return Ok(());
     }
 
-    let target = BootTarget::from_bytes(&store.data_bytes)?;
+    let mut target = BootTarget::from_bytes(&store.data_bytes)?;
 
     if args.current_target {
         println!("Boot target: {target:?}");
+        if let Some(pending) = schedule::pending()? {
+            println!("{pending}");
+        }
+        if let Some(wake) = rtc::wake_alarm(&args.rtc)? {
+            let wake = schedule::format_local_time(wake);
+            println!("Wake alarm set for {wake}, will boot the target above");
+        }
+        return Ok(());
+    }
+
+    if args.cancel {
+        sudo::escalate_if_needed()
+            .expect("sudo failed, you may also call rbtw with sudo in front of it");
+        schedule::cancel()?;
+        target
+            .disarm()
+            .wrap_err("Could not undo configuring the next boot")?;
+        println!("Scheduled reboot cancelled, the next boot goes to the default OS");
         return Ok(());
     }
 
+    let option_changes = args.option_changes();
+    // logind can only arm a normal reboot, kexec needs us to load the kernel
+    // and a wake alarm needs us to write the rtc
+    let logind_suffices =
+        !args.make_default && !args.kexec && args.wake_at.is_none() && option_changes.is_empty();
+    let unprivileged = if logind_suffices {
+        target
+            .unprivileged_entry()
+            .wrap_err("Failed to configure next boot through logind")?
+    } else {
+        None
+    };
+    if let Some(entry) = unprivileged {
+        check_can_hibernate(&args)?;
+        warn_if_hibernated(&args, &target);
+        logind::set_reboot_to_boot_loader_entry(entry.id())
+            .wrap_err("Failed to configure next boot through logind")?;
+        return leave_os(&args);
+    }
+
     sudo::escalate_if_needed()
         .expect("sudo failed, you may also call rbtw with sudo in front of it");
 
+    // SECURITY: kernel options such as init= give a root shell, with the
+    // setuid bit anyone could pass them
+    if !option_changes.is_empty() && !real_root {
+        return Err(eyre!("Changing kernel options needs super user rights")
+            .suggestion("Run rbtw with sudo in front of it")
+            .into());
+    }
+
     let was_set = setuid::is_set();
 
     let path = std::env::current_exe().unwrap();
     chown(path, Some(ROOT), Some(ROOT)).unwrap();
     setuid::set();
 
+    // the target of an earlier run has booted by now
+    efi::remove_stale_temporary_entries(&mut efibootnext::Adapter::default())
+        .wrap_err("Could not remove temporary efi entries")?;
+    bootctl::remove_temporary_entries().wrap_err("Could not remove temporary loader entries")?;
+
+    // after escalating, we only get here once even if sudo restarted us
+    check_can_hibernate(&args)?;
+    warn_if_hibernated(&args, &target);
+
     if !was_set {
         let path = std::env::args().next().unwrap();
         println!(
@@ -85,25 +353,116 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             - next time you can run without sudo!\n\
             - next time reboot will happen instandly"
         );
-        if !args.no_reboot {
+        if !args.no_reboot && args.scheduled().is_none() {
+            let action = if args.power_off || args.wake_at.is_some() {
+                "powering off"
+            } else if args.hibernate {
+                "hibernating"
+            } else {
+                "rebooting"
+            };
             for i in (1..=10).rev() {
-                print!("\rrebooting in {i}s ");
+                print!("\r{action} in {i}s ");
                 std::io::stdout().flush().unwrap();
                 sleep(Duration::from_secs(1));
             }
         }
     }
 
-    target
-        .configure_next_boot()
-        .wrap_err("Failed to configure next boot")
-        .with_note(|| format!("tried to find OS matching: {target:?}"))?;
+    if let Some(risk) = target
+        .bitlocker_risk()
+        .wrap_err("Could not check for BitLocker")?
+    {
+        match bitlocker::ask_user(&risk)? {
+            Answer::Continue => (),
+            Answer::ContinueDontAskAgain => {
+                target.acknowledge_bitlocker();
+                store.set_data(&target.to_bytes())?;
+            }
+            Answer::Abort => return Ok(()),
+        }
+    }
+
+    if args.kexec {
+        match target.load_kexec().and_then(|()| kexec::reboot()) {
+            Ok(()) => return Ok(()),
+            Err(report) => {
+                // a loaded kernel would be started by the normal reboot too
+                let _ = kexec::unload();
+                showln!("Could not reboot using kexec, doing a normal reboot instead\n{report:?}");
+            }
+        }
+    }
+
+    if args.make_default {
+        target
+            .make_default()
+            .wrap_err("Failed to make the target the default")
+            .with_note(|| format!("tried to find OS matching: {target:?}"))?;
+        println!("Target is now the default OS, undo with: --restore-default");
+    } else {
+        target
+            .configure_next_boot(&option_changes)
+            .wrap_err("Failed to configure next boot")
+            .with_note(|| format!("tried to find OS matching: {target:?}"))?;
+    }
+
+    leave_os(&args)
+}
+
+/// Runs where [`warn_if_hibernated`] runs, so its notes show once
+fn check_can_hibernate(args: &Args) -> Result<()> {
+    if args.hibernate {
+        power::check_hibernation_configured().wrap_err("Can not hibernate")?;
+    }
+    Ok(())
+}
 
-    if !args.no_reboot {
-        Command::new("reboot")
-            .arg("now")
-            .status()
-            .wrap_err("Failed to call reboot")?;
+/// Only warns, a failed check must not stop the reboot
+fn warn_if_hibernated(args: &Args, target: &BootTarget) {
+    if args.no_reboot {
+        return;
+    }
+    if let Err(report) = ntfs::warn_if_hibernated(target.is_windows()) {
+        eprintln!("Could not check for hibernated Windows volumes: {report:?}");
+    }
+}
+
+/// For commands the setuid bit must not make available to every user
+fn require_sudo(real_root: bool, what: &str) -> Result<()> {
+    if real_root {
+        Ok(())
+    } else {
+        Err(eyre!("{what} needs super user rights")).suggestion("Run rbtw with sudo in front of it")
+    }
+}
+
+/// Reboot or otherwise leave the OS as requested, the next boot has been
+/// configured already.
+fn leave_os(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.no_reboot {
+        return Ok(());
+    }
+
+    if let Some(when) = args.scheduled() {
+        schedule::schedule(when, args.power_off).wrap_err("Failed to schedule reboot")?;
+        if let Some(pending) = schedule::pending()? {
+            println!("{pending}");
+        }
+        println!("Cancel with: --cancel");
+    } else if let Some(time) = args.wake_at {
+        let at = schedule::next_occurrence(time);
+        rtc::set_wake_alarm(&args.rtc, at).wrap_err("Failed to set wake alarm")?;
+        let at = schedule::format_local_time(at);
+        println!("Powering off, will wake up at {at} and boot the target");
+        power::power_off().wrap_err("Failed to power off")?;
+    } else if args.power_off {
+        println!("Powering off, the boot target stays armed until the next boot");
+        power::power_off().wrap_err("Failed to power off")?;
+    } else if args.hibernate {
+        power::hibernate().wrap_err("Failed to hibernate")?;
+    } else {
+        power::reboot().wrap_err("Failed to reboot")?;
     }
     Ok(())
 }
@@ -0,0 +1,118 @@
+//! Windows hibernates, also when "shutting down" with Fast Startup on, by
+//! writing its memory to `hiberfil.sys`. Until it resumes its NTFS volumes
+//! are still in use. Anything another OS writes to them meanwhile is lost or
+//! corrupts the volume once Windows resumes.
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::Result;
+use itertools::Itertools;
+
+use crate::disk::{self, TempMount};
+use crate::showln;
+
+const NTFS_OEM_ID: &[u8; 8] = b"NTFS    ";
+const NTFS_MOUNT_TYPES: &[&str] = &["ntfs", "ntfs3", "fuseblk"];
+
+/// Signatures at the start of a hiberfil.sys that Windows still intends to
+/// resume from. After resuming it is overwritten with `wake` or zeros.
+fn is_live_hiberfil(start: &[u8]) -> bool {
+    matches!(start, b"hibr" | b"HIBR")
+}
+
+/// A volume we can not check is skipped, this only decides whether to warn
+fn hibernated(root: &Path, device: &Path) -> bool {
+    match disk::read_start(&root.join("hiberfil.sys"), 4) {
+        Ok(start) => start.as_deref().is_some_and(is_live_hiberfil),
+        Err(report) => {
+            eprintln!(
+                "Could not check {} for hibernated Windows, skipping it: {report}",
+                device.display()
+            );
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HibernatedVolume {
+    pub device: PathBuf,
+    /// Mounted read-write by the running linux
+    pub mounted_rw: bool,
+}
+
+/// NTFS volumes Windows has hibernated. Volumes that are not mounted can only
+/// be inspected as root, without it only mounted ones are checked.
+pub fn hibernated_volumes() -> Result<Vec<HibernatedVolume>> {
+    let mounts = disk::mounts()?;
+    let mut found = Vec::new();
+    for mount in mounts
+        .iter()
+        .filter(|m| NTFS_MOUNT_TYPES.contains(&m.fs_type.as_str()))
+    {
+        if hibernated(&mount.mount_point, &mount.device) {
+            found.push(HibernatedVolume {
+                device: mount.device.clone(),
+                mounted_rw: mount.read_write,
+            });
+        }
+    }
+
+    if !nix::unistd::geteuid().is_root() {
+        return Ok(found);
+    }
+
+    for device in disk::partitions()? {
+        if mounts.iter().any(|m| m.device == device) {
+            continue;
+        }
+        if disk::oem_id(&device).ok().as_ref() != Some(NTFS_OEM_ID) {
+            continue;
+        }
+        // Without the ntfs3 driver we can not look, nothing to warn about then
+        let Ok(mount) = TempMount::new(&device, "ntfs3") else {
+            continue;
+        };
+        if hibernated(&mount.path, &device) {
+            found.push(HibernatedVolume {
+                device,
+                mounted_rw: false,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Give the user a moment to abort if rebooting now could cost them data
+pub fn warn_if_hibernated(target_is_windows: bool) -> Result<()> {
+    let volumes = hibernated_volumes()?;
+    if volumes.is_empty() {
+        return Ok(());
+    }
+
+    let list = volumes
+        .iter()
+        .map(|v| {
+            if v.mounted_rw {
+                format!("{} (mounted read-write here!)", v.device.display())
+            } else {
+                v.device.display().to_string()
+            }
+        })
+        .join("\n  - ");
+
+    if target_is_windows {
+        showln!(
+            "Windows is hibernated or has Fast Startup on, booting it resumes \
+            that session. Changes made from linux to these volumes will be lost \
+            or corrupt them:\n  - {list}"
+        );
+    } else {
+        showln!(
+            "Windows is hibernated or has Fast Startup on. The OS you are \
+            rebooting to must not write to these volumes, that risks data loss:\
+            \n  - {list}\nTurn off Fast Startup in Windows or run: powercfg /h off"
+        );
+    }
+    Ok(())
+}
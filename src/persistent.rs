@@ -0,0 +1,79 @@
+//! Changing the default OS, or showing the menu on every boot, rather then
+//! only for the next boot. Before the first change we back up what we
+//! overwrite so `restore` can put it back exactly.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Result, Section};
+use serde::{Deserialize, Serialize};
+
+use crate::{bootctl, efi};
+
+const BACKUP: &str = "/var/lib/rbtw/default-backup.ron";
+
+/// None for values that were not set
+#[derive(Debug, Serialize, Deserialize)]
+struct Backup {
+    boot_order: Option<Vec<u16>>,
+    loader_entry_default: Option<String>,
+    loader_config_timeout: Option<String>,
+}
+
+fn read_backup() -> Result<Option<Backup>> {
+    match fs::read_to_string(BACKUP) {
+        Ok(s) => ron::from_str(&s)
+            .wrap_err("Could not deserialize backup of the default OS")
+            .with_note(|| format!("path: {BACKUP}"))
+            .map(Some),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+            .wrap_err("Could not read backup of the default OS")
+            .with_note(|| format!("path: {BACKUP}")),
+    }
+}
+
+/// Keeps an existing backup, that has the state from before rbtw changed
+/// the default.
+pub fn backup() -> Result<()> {
+    if read_backup()?.is_some() {
+        return Ok(());
+    }
+    let backup = Backup {
+        boot_order: efi::boot_order().ok(),
+        loader_entry_default: bootctl::loader_entry_default(),
+        loader_config_timeout: bootctl::loader_config_timeout(),
+    };
+    let dir = Path::new(BACKUP)
+        .parent()
+        .expect("backup is in a directory");
+    fs::create_dir_all(dir)
+        .wrap_err("Could not create directory for backup")
+        .with_note(|| format!("path: {}", dir.display()))?;
+    let s = ron::to_string(&backup).expect("Ron can serialize numbers and strings");
+    fs::write(BACKUP, s)
+        .wrap_err("Could not write backup of the default OS")
+        .with_note(|| format!("path: {BACKUP}"))
+}
+
+/// Undo every change to the default since the backup
+pub fn restore() -> Result<()> {
+    let Some(backup) = read_backup()? else {
+        return Err(eyre!("There is no backup of the default OS")).note(
+            "The default was never changed with --make-default or --menu-always, \
+                or it was already restored",
+        );
+    };
+    if let Some(order) = backup.boot_order {
+        efi::set_boot_order(order)?;
+    }
+    bootctl::set_loader_entry_default(backup.loader_entry_default.as_deref())
+        .wrap_err("Could not restore the systemd-boot default")?;
+    bootctl::set_loader_config_timeout(backup.loader_config_timeout.as_deref())
+        .wrap_err("Could not restore the systemd-boot menu timeout")?;
+    fs::remove_file(BACKUP)
+        .wrap_err("Could not remove restored backup")
+        .with_note(|| format!("path: {BACKUP}"))
+}
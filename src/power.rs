@@ -0,0 +1,91 @@
+//! The ways we can leave the current OS once the next boot is configured
+
+use std::fs;
+use std::process::Command;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Result, Section};
+use itertools::Itertools;
+
+use crate::{disk, showln};
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .wrap_err_with(|| format!("Failed to call {program}"))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(eyre!("{program} failed")).with_note(|| format!("exit status: {status}"))
+    }
+}
+
+pub fn reboot() -> Result<()> {
+    run("reboot", &["now"])
+}
+
+/// The firmware keeps BootNext and the systemd-boot oneshot until the machine
+/// boots again, however long it stays off.
+pub fn power_off() -> Result<()> {
+    run("poweroff", &[])
+}
+
+/// Hibernating needs swap to store memory in and a resume device so the next
+/// linux boot picks the session up again.
+pub fn check_hibernation_configured() -> Result<()> {
+    let states =
+        fs::read_to_string("/sys/power/state").wrap_err("Could not read /sys/power/state")?;
+    if !states.split_whitespace().any(|state| state == "disk") {
+        return Err(eyre!("This kernel does not support hibernation"))
+            .with_note(|| format!("supported power states: {}", states.trim()));
+    }
+
+    let swaps = fs::read_to_string("/proc/swaps").wrap_err("Could not read /proc/swaps")?;
+    // first line is the header
+    if swaps.lines().skip(1).all(|line| line.trim().is_empty()) {
+        return Err(eyre!("No swap is active, it is needed to hibernate"))
+            .suggestion("Set up a swap partition or file at least the size of your RAM");
+    }
+
+    let resume = fs::read_to_string("/sys/power/resume").unwrap_or_default();
+    if resume.trim().is_empty() || resume.trim() == "0:0" {
+        showln!(
+            "No resume device configured (resume= kernel option). \
+            Unless your systemd passes it on through the HibernateLocation \
+            efi variable the session will be lost."
+        );
+    }
+    Ok(())
+}
+
+/// Filesystems the other OS could also write to. Writing to them from there
+/// while we are hibernated corrupts them once we resume.
+fn shared_mounts() -> Result<Vec<String>> {
+    const SHARED_TYPES: &[&str] = &["vfat", "exfat", "ntfs", "ntfs3", "fuseblk"];
+    Ok(disk::mounts()?
+        .into_iter()
+        .filter(|m| m.read_write && SHARED_TYPES.contains(&m.fs_type.as_str()))
+        .map(|m| {
+            format!(
+                "{} on {} ({})",
+                m.device.display(),
+                m.mount_point.display(),
+                m.fs_type
+            )
+        })
+        .collect())
+}
+
+/// Suspend this linux to disk, the next boot goes to the configured target.
+pub fn hibernate() -> Result<()> {
+    let shared = shared_mounts()?;
+    if !shared.is_empty() {
+        showln!(
+            "The other OS must not write to filesystems this hibernated \
+            linux has mounted read-write. These are:\n  - {}",
+            shared.iter().join("\n  - ")
+        );
+    }
+    run("systemctl", &["hibernate"])
+}
@@ -0,0 +1,41 @@
+//! Let the machine power on by itself using the wake alarm of the real time
+//! clock. The kernel takes and gives the alarm in seconds since the unix
+//! epoch regardless of whether the hardware clock runs in UTC or local time.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use color_eyre::eyre::Context;
+use color_eyre::{Result, Section};
+
+pub const DEFAULT_RTC: &str = "/sys/class/rtc/rtc0";
+
+/// `at` is in seconds since the unix epoch
+pub fn set_wake_alarm(rtc: &Path, at: i64) -> Result<()> {
+    let path = rtc.join("wakealarm");
+    // the kernel refuses to overwrite an alarm that is still pending
+    fs::write(&path, "0")
+        .wrap_err("Could not clear existing wake alarm")
+        .with_note(|| format!("path: {}", path.display()))?;
+    fs::write(&path, at.to_string())
+        .wrap_err("Could not set wake alarm")
+        .with_note(|| format!("path: {}", path.display()))
+        .suggestion("Check if your firmware allows waking on RTC alarm")
+}
+
+/// The pending wake alarm in seconds since the unix epoch
+pub fn wake_alarm(rtc: &Path) -> Result<Option<i64>> {
+    let path = rtc.join("wakealarm");
+    let alarm = match fs::read_to_string(&path) {
+        Ok(alarm) => alarm,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .wrap_err("Could not read wake alarm")
+                .with_note(|| format!("path: {}", path.display()))
+        }
+    };
+    // empty when no alarm is set
+    Ok(alarm.trim().parse().ok())
+}
@@ -0,0 +1,211 @@
+//! Reboot or power off at a later moment. We hand the waiting to systemd's
+//! `shutdown` so logind keeps track of it, warns logged in users with wall
+//! messages as the moment nears and lets it be cancelled.
+
+use std::fmt::Display;
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Result, Section};
+use nix::libc;
+
+/// Written by logind while a shutdown is scheduled
+const SCHEDULED: &str = "/run/systemd/shutdown/scheduled";
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClockTime {
+    pub hour: u8,
+    pub minute: u8,
+}
+
+/// Parses `HH:MM` in 24 hour format
+pub fn parse_clock_time(s: &str) -> Result<ClockTime, String> {
+    let (hour, minute) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected a time like 08:00, got: {s}"))?;
+    let hour: u8 = hour.parse().map_err(|e| format!("invalid hour: {e}"))?;
+    let minute: u8 = minute.parse().map_err(|e| format!("invalid minute: {e}"))?;
+    if hour > 23 || minute > 59 {
+        return Err(format!("not a time of day: {s}"));
+    }
+    Ok(ClockTime { hour, minute })
+}
+
+/// Parses a delay such as `90s`, `10m`, `2h` or `1h30m`. A number without
+/// unit is taken as minutes.
+pub fn parse_delay(s: &str) -> Result<Duration, String> {
+    if let Ok(minutes) = s.parse::<u64>() {
+        return minutes
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("delay too long: {s}"));
+    }
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            _ => return Err(format!("unknown unit '{c}', use s, m or h")),
+        };
+        let value: u64 = number
+            .parse()
+            .map_err(|_| format!("expected a number before '{c}'"))?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(|| format!("delay too long: {s}"))?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(format!("missing unit after {number}, use s, m or h"));
+    }
+    Ok(Duration::from_secs(total))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum When {
+    At(ClockTime),
+    In(Duration),
+}
+
+impl When {
+    /// In the format `shutdown` expects
+    fn shutdown_arg(&self) -> String {
+        match self {
+            When::At(ClockTime { hour, minute }) => format!("{hour:02}:{minute:02}"),
+            // shutdown only knows whole minutes, rather late then early
+            When::In(delay) => format!("+{}", delay.as_secs().div_ceil(60)),
+        }
+    }
+}
+
+pub fn schedule(when: When, power_off: bool) -> Result<()> {
+    let (mode, message) = if power_off {
+        (
+            "--poweroff",
+            "rbtw: powering off, next boot goes to another OS",
+        )
+    } else {
+        ("--reboot", "rbtw: rebooting into another OS")
+    };
+    let time = when.shutdown_arg();
+    let status = Command::new("shutdown")
+        .args([mode, &time, message])
+        .status()
+        .wrap_err("Failed to call shutdown")?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(eyre!("shutdown failed"))
+            .with_note(|| format!("exit status: {status}"))
+            .with_note(|| format!("time argument: {time}"))
+    }
+}
+
+pub fn cancel() -> Result<()> {
+    let status = Command::new("shutdown")
+        .arg("-c")
+        .status()
+        .wrap_err("Failed to call shutdown")?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(eyre!("Could not cancel scheduled shutdown"))
+            .with_note(|| format!("exit status: {status}"))
+    }
+}
+
+pub struct Pending {
+    mode: String,
+    /// seconds since the unix epoch
+    at: i64,
+}
+
+impl Display for Pending {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} scheduled at {}",
+            self.mode,
+            format_local_time(self.at)
+        )
+    }
+}
+
+/// The scheduled reboot or power off if there is one
+pub fn pending() -> Result<Option<Pending>> {
+    let content = match fs::read_to_string(SCHEDULED) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).wrap_err("Could not read scheduled shutdown"),
+    };
+
+    let mut mode = None;
+    let mut usec = None;
+    for line in content.lines() {
+        match line.split_once('=') {
+            Some(("MODE", value)) => mode = Some(value.to_string()),
+            Some(("USEC", value)) => usec = value.parse::<i64>().ok(),
+            _ => (),
+        }
+    }
+
+    Ok(mode.zip(usec).map(|(mode, usec)| Pending {
+        mode,
+        at: usec / 1_000_000,
+    }))
+}
+
+/// The first moment from now the local clock reads `time`, in seconds since
+/// the unix epoch
+pub fn next_occurrence(time: ClockTime) -> i64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("system clock is past 1970")
+        .as_secs()
+        .try_into()
+        .expect("time_t fits the current time");
+    // SAFETY: all zero is a valid tm, localtime_r only writes to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    tm.tm_hour = time.hour.into();
+    tm.tm_min = time.minute.into();
+    tm.tm_sec = 0;
+    // let mktime figure out daylight saving
+    tm.tm_isdst = -1;
+
+    let mut at = unsafe { libc::mktime(&mut tm) };
+    if at <= now {
+        // mktime normalizes the day overflowing the month
+        tm.tm_mday += 1;
+        tm.tm_isdst = -1;
+        at = unsafe { libc::mktime(&mut tm) };
+    }
+    at as i64
+}
+
+/// Seconds since the unix epoch as local `YYYY-MM-DD HH:MM`
+pub fn format_local_time(epoch: i64) -> String {
+    // SAFETY: all zero is a valid tm, localtime_r only writes to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&epoch, &mut tm) }.is_null() {
+        return format!("{epoch} (seconds since 1970)");
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
@@ -0,0 +1,160 @@
+//! An escape hatch: every efi variable that decides what boots, saved to a
+//! file and written back exactly. That is `BootOrder`, `BootNext`, the
+//! `Boot####` entries and the `Loader*` variables of systemd-boot.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Context;
+use color_eyre::{Result, Section};
+use efivar::efi::{Variable, VariableFlags, VariableVendor};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::bootctl::{efi_protection, SYSTEMD_BOOT};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Var {
+    name: String,
+    vendor: Uuid,
+    /// `EFI_VARIABLE_*` attribute bits
+    attributes: u32,
+    data: Vec<u8>,
+}
+
+impl Var {
+    fn variable(&self) -> Variable {
+        Variable::new_with_vendor(&self.name, self.vendor)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    variables: Vec<Var>,
+}
+
+fn is_boot_related(var: &Variable) -> bool {
+    if *var.vendor() == VariableVendor::Efi {
+        ["BootOrder", "BootNext"].contains(&var.name()) || var.boot_var_id().is_some()
+    } else {
+        *var.vendor() == SYSTEMD_BOOT && var.name().starts_with("Loader")
+    }
+}
+
+fn efivarfs_path(var: &Variable) -> PathBuf {
+    let vendor: &Uuid = var.vendor().as_ref();
+    PathBuf::from(format!("/sys/firmware/efi/efivars/{}-{vendor}", var.name()))
+}
+
+/// systemd-boot's variables are marked immutable by the kernel
+fn is_protected(var: &Variable) -> bool {
+    *var.vendor() == SYSTEMD_BOOT && efivarfs_path(var).is_file()
+}
+
+fn current() -> Result<Vec<Variable>> {
+    Ok(efivar::system()
+        .get_all_vars()
+        .wrap_err("Could not list efi variables")?
+        .filter(is_boot_related)
+        .collect())
+}
+
+/// Saves the boot related efi variables to `path`
+pub fn save(path: &Path) -> Result<usize> {
+    let vars = efivar::system();
+    let mut variables = Vec::new();
+    for var in current()? {
+        let (data, flags) = vars
+            .read(&var)
+            .wrap_err("Could not read efi variable")
+            .with_note(|| format!("variable: {var}"))?;
+        variables.push(Var {
+            name: var.name().to_string(),
+            vendor: *var.vendor().as_ref(),
+            attributes: flags.bits(),
+            data,
+        });
+    }
+    let count = variables.len();
+    let s = ron::ser::to_string_pretty(&Snapshot { variables }, Default::default())
+        .expect("Ron can serialize numbers and strings");
+    fs::write(path, s)
+        .wrap_err("Could not write efi variable snapshot")
+        .with_note(|| format!("path: {}", path.display()))?;
+    Ok(count)
+}
+
+fn write(var: &Var) -> Result<()> {
+    let variable = var.variable();
+    if is_protected(&variable) {
+        efi_protection::remove(&efivarfs_path(&variable))
+            .wrap_err("Could not remove immutable flag protecting efi variable")?;
+    }
+    efivar::system()
+        .write(
+            &variable,
+            VariableFlags::from_bits_truncate(var.attributes),
+            &var.data,
+        )
+        .wrap_err("Could not write efi variable")
+        .with_note(|| format!("variable: {variable}"))?;
+    if *variable.vendor() == SYSTEMD_BOOT {
+        efi_protection::add(&efivarfs_path(&variable))
+            .wrap_err("Could not re-add immutable flag protecting efi variable")?;
+    }
+    Ok(())
+}
+
+fn delete(var: &Variable) -> Result<()> {
+    if is_protected(var) {
+        efi_protection::remove(&efivarfs_path(var))
+            .wrap_err("Could not remove immutable flag protecting efi variable")?;
+    }
+    efivar::system()
+        .delete(var)
+        .wrap_err("Could not remove efi variable")
+        .with_note(|| format!("variable: {var}"))
+}
+
+/// Puts back the variables saved in `path`. Boot related variables created
+/// since are removed. Variables that only live until the next boot, such as
+/// `LoaderInfo`, are left to systemd-boot.
+pub fn restore(path: &Path) -> Result<()> {
+    let s = fs::read_to_string(path)
+        .wrap_err("Could not read efi variable snapshot")
+        .with_note(|| format!("path: {}", path.display()))?;
+    let snapshot: Snapshot = ron::from_str(&s)
+        .wrap_err("Could not deserialize efi variable snapshot")
+        .with_note(|| format!("path: {}", path.display()))?;
+
+    let non_volatile = |attributes: u32| {
+        VariableFlags::from_bits_truncate(attributes).contains(VariableFlags::NON_VOLATILE)
+    };
+    // entries before the order and next boot that point to them
+    let (entries, others): (Vec<_>, Vec<_>) = snapshot
+        .variables
+        .iter()
+        .filter(|var| non_volatile(var.attributes))
+        .partition(|var| var.variable().boot_var_id().is_some());
+    for var in entries.into_iter().chain(others) {
+        write(var)?;
+    }
+
+    let vars = efivar::system();
+    for var in current()? {
+        if snapshot
+            .variables
+            .iter()
+            .any(|saved| saved.variable() == var)
+        {
+            continue;
+        }
+        let Ok((_, flags)) = vars.read(&var) else {
+            continue;
+        };
+        if flags.contains(VariableFlags::NON_VOLATILE) {
+            delete(&var)?;
+        }
+    }
+    Ok(())
+}